@@ -1,5 +1,5 @@
 // Imports necessary modules and functions
-use crate::create_whitelist_from_connected_devices_main;
+use crate::list_connected_devices_main;
 use std::sync::atomic::Ordering;
 use notify_rust::Notification;
 use crossterm::{
@@ -10,13 +10,12 @@ use crossterm::{
 use super::device_functions::{DeviceList, DeviceEntry};
 use tui::widgets::Wrap;
 use std::{
-    collections::HashSet,
+    collections::{BTreeSet, HashSet},
     error::Error,
     io,
     sync::mpsc,
     thread,
     time::{Duration, Instant},
-    path::Path,
 };
 use tui::{
     backend::CrosstermBackend,
@@ -29,16 +28,30 @@ use tui::{
 use crate::get_logs;
 use crate::clear_logs;
 use crate::DeviceMonitor;
-use crate::detect_new_media_mount_main;
-use crate::start_hash_checker;
 use crate::push_log;
 use crate::WHITELIST;
 use crate::WHITELIST_READY;
+use crate::WHITELIST_FIRST_RUN;
+use crate::WHITELIST_CHANGED;
+use crate::whitelist_device_main;
+use crate::finish_whitelist_setup;
+use crate::rollback_feed_main;
+use crate::engine::whitelist::{find_whitelist_entry, DeviceIdentity, MatchPolicy};
+use crate::engine::quarantine::{forget, log_quarantine, reblock_if_quarantined, unblock};
+use crate::engine::session_log::{log_sessions, show_session};
+use crate::engine::keylogger::disconnect_pending;
+use crate::engine::scan_jobs::{cancel_scan, log_scan_status, log_scans, MediaScanTrigger};
+use crate::engine::usb_monitor::{subscribe, UsbEvent};
+use crate::engine::usb_auth::{
+    is_device_authorized, list_device_interfaces, set_device_authorized,
+    set_interface_authorized, DefaultDeny,
+};
 
 
 // Enum to differentiate between keyboard input and timed tick events
 enum Event<I> {
     Input(I),
+    Usb(UsbEvent),
     Tick,
 }
 
@@ -48,6 +61,115 @@ enum Focus {
     Whitelist,
 }
 
+// Lines for the whitelist panel
+fn whitelist_lines() -> Vec<String> {
+    let wl = WHITELIST.read().unwrap();
+    wl.iter()
+        .map(|entry| format!("[{}] {} ({})", entry.identity.id(), entry.identity.name(), entry.policy))
+        .collect()
+}
+
+// Allow the device to communicate with OS after it was approved.
+// When only some interfaces were approved the rest of them stay blocked
+fn authorize_device(port_path: &str, approved_interfaces: Option<&BTreeSet<u8>>) {
+    if !is_device_authorized(port_path) {
+        match set_device_authorized(port_path, true) {
+            Ok(_) => push_log(format!("[ACTION] Device [{}] authorized (authorized=1)", port_path)),
+            Err(e) => {
+                push_log(format!("[ERROR] Failed to authorize device [{}]: {}", port_path, e));
+                return;
+            }
+        }
+    }
+    for interface in list_device_interfaces(port_path) {
+        let allow = approved_interfaces.is_none_or(|approved| approved.contains(&interface.number));
+        if let Err(e) = set_interface_authorized(port_path, interface.number, allow) {
+            push_log(format!("[ERROR] Failed to change interface {} of [{}]: {}", interface.number, port_path, e));
+        } else if !allow {
+            push_log(format!("[ACTION] Interface {} ({}) of [{}] stays blocked", interface.number, interface.class_name(), port_path));
+        }
+    }
+}
+
+// Reacts to USB device connections and disconnections from the udev monitor
+fn handle_usb_event(
+    usb_event: UsbEvent,
+    device_list: &mut DeviceList,
+    known_devices: &mut HashSet<String>,
+    default_deny: bool,
+) {
+    match usb_event {
+        UsbEvent::Added(identity) => {
+            if known_devices.insert(identity.port_path.clone()) {
+                check_new_device(&identity, device_list, default_deny);
+            }
+        }
+        UsbEvent::Changed(identity) => {
+            // Strings and interfaces of the device could be read only after it was authorized
+            let id = identity.port_path.clone();
+            if let Some(index) = (0..100).find(|&i| device_list.get(i).map(|d| &d.id) == Some(&id)) {
+                if let Some(entry) = device_list.get_mut(index) {
+                    entry.interfaces = list_device_interfaces(&id);
+                    entry.identity = identity;
+                }
+            }
+        }
+        UsbEvent::Removed { port_path: id } => {
+            known_devices.remove(&id);
+            // Try to find and remove device from device_list
+            if let Some(index) = (0..100).find(|&i| device_list.get(i).map(|d| &d.id) == Some(&id)) {
+                if let Err(e) = device_list.remove_device(index) {
+                    push_log(format!("> Failed to remove disconnected device [{}]: {}", id, e));
+                } else {
+                    push_log(format!("> Device [{}] disconnected and removed from review device list", id));
+                }
+            }
+        }
+    }
+}
+
+// Checks the device that was just connected against the whitelist and quarantine
+fn check_new_device(identity: &DeviceIdentity, device_list: &mut DeviceList, default_deny: bool) {
+    let id = identity.port_path.clone();
+    // Whitelisted devices are authorized automatically in DefaultDeny mode
+    if let Some(trusted) = find_whitelist_entry(identity) {
+        if default_deny && !is_device_authorized(&id) {
+            authorize_device(&id, trusted.approved_interfaces.as_ref());
+        }
+        return;
+    }
+    // Device that was blocked before is blocked again, no review is needed
+    if reblock_if_quarantined(identity) {
+        return;
+    }
+    // If device is unknown, warn and add to tracked list
+    push_log(format!("[ALERT] Unknown device {} connected!", identity.describe()));
+    Notification::new()
+        .summary("USB Device Alert")
+        .body(&format!("Unknown device connected:\n[{}] {}", identity.id(), identity.name()))
+        .icon("dialog-warning")
+        .show()
+        .ok(); 
+
+    let entry = DeviceEntry {
+        id: id.clone(),
+        identity: identity.clone(),
+        interfaces: list_device_interfaces(&id),
+        allowed_interfaces: BTreeSet::new(),
+    };
+    match device_list.add_device(entry) {
+        Ok(index) => {
+            push_log(format!("> Added device [{}] to slot {}", id, index));
+            if default_deny {
+                push_log(format!("[SECURITY] Device [{}] is not authorized, use wadd {} to allow it", id, index));
+            }
+        }
+        Err(e) => {
+            push_log(format!("> Failed to add device [{}]: {}", id, e));
+        }
+    }
+}
+
 // Entry point for the CLI interface
 pub fn run_cli() -> Result<(), Box<dyn Error>> {
     if std::env::var("USER").unwrap_or_default() != "root" {
@@ -61,11 +183,16 @@ pub fn run_cli() -> Result<(), Box<dyn Error>> {
     let mut whitelist_changed = true;
     // DeviceList to hold detected but not whitelisted USB devices
     let mut device_list = DeviceList::new(100);
-    let mut safe_connection = false;
+    // Flash drives of unknown devices are scanned by the engine while SafeConnection mode is enabled
+    let mut media_scan_trigger: Option<MediaScanTrigger> = None;
     let mut lockdown = false;
+    // New devices are not authorized until approved with wadd, the default is restored on every exit
+    let mut default_deny = DefaultDeny::default();
     // Channel for communicating between UI input and tick thread
     let (tx, rx) = mpsc::channel();
+    let input_tx = tx.clone();
     thread::spawn(move || {
+        let tx = input_tx;
         let tick_rate = Duration::from_millis(200);
         let mut last_tick = Instant::now();
         loop {
@@ -82,30 +209,24 @@ pub fn run_cli() -> Result<(), Box<dyn Error>> {
         }
     });
 
-    // Load initial known devices from whitelist
-    let mut known_devices: HashSet<String> = {
-        let whitelist = WHITELIST.read().unwrap();
-        whitelist.keys().cloned().collect()
-    };
+    // USB events come from the udev monitor instead of polling
+    let usb_rx = subscribe();
+    let usb_tx = tx.clone();
+    thread::spawn(move || {
+        for usb_event in usb_rx {
+            if usb_tx.send(Event::Usb(usb_event)).is_err() {
+                break;
+            }
+        }
+    });
 
-    let mut logs = vec!["[INFO] USB Device Monitor Started".to_string()];
+    // Port paths of the devices that were already checked, devices attached
+    // on startup are checked against the whitelist once it is ready
+    let mut known_devices: HashSet<String> = HashSet::new();
+    let mut startup_devices_checked = false;
+    let mut pending_usb_events: Vec<UsbEvent> = Vec::new();
 
-    // Log any unexpected devices on startup
-    for device in &known_devices {
-        let whitelist = WHITELIST.read().unwrap();
-        if !whitelist.contains_key(device) {
-            push_log(format!("[ALERT] Unrecognized device on startup: '{}'", device));
-            Notification::new()
-                .summary("USB Device Warning")
-                .body(&format!("Unrecognized device detected at startup:\n{}", device))
-                .icon("dialog-warning")
-                .show()
-                .ok();
-
-        } else {
-            push_log(format!("[INFO] Whitelisted device on startup: '{}'", device));
-        }
-    }
+    let mut logs = vec!["[INFO] USB Device Monitor Started".to_string()];
 
     // UI and command loop
     let mut input = String::new();
@@ -115,24 +236,17 @@ pub fn run_cli() -> Result<(), Box<dyn Error>> {
     let max_visible_whitelist: usize = 25;
     let mut focus = Focus::Logs;
     let mut device_monitor = DeviceMonitor::new();
-    let mut whitelist_vec = {
-        let wl = WHITELIST.read().unwrap();
-        wl.iter()
-            .map(|(id, name)| format!("[{}] {}", id, name))
-            .collect::<Vec<String>>()
-    };
+    let mut whitelist_vec = whitelist_lines();
     loop {
         // Append new logs on every iteration
         logs.extend(get_logs());
         clear_logs();
         // Render whitelist for display
+        if WHITELIST_CHANGED.swap(false, Ordering::SeqCst) {
+            whitelist_changed = true;
+        }
         if whitelist_changed {
-            whitelist_vec = {
-                let wl = WHITELIST.read().unwrap();
-                wl.iter()
-                    .map(|(id, name)| format!("[{}] {}", id, name))
-                    .collect::<Vec<String>>()
-            };
+            whitelist_vec = whitelist_lines();
             if WHITELIST_READY.load(Ordering::SeqCst){
                 whitelist_changed = false;
             }
@@ -230,9 +344,6 @@ pub fn run_cli() -> Result<(), Box<dyn Error>> {
                     // Command parsing
                     match input.trim() {
                         ":q" | "exit" => {
-                            if lockdown{
-                                device_monitor.stop();
-                            }
                             break;
                         }
 
@@ -244,20 +355,33 @@ pub fn run_cli() -> Result<(), Box<dyn Error>> {
                             whitelist_changed = true;
                             // Add selected device to whitelist
                             let parts: Vec<&str> = cmd.split_whitespace().collect();
-                            if parts.len() == 2 {
+                            // Optional third argument chooses how strictly the device is matched later
+                            let policy = match parts.get(2) {
+                                Some(value) => MatchPolicy::parse(value),
+                                None => Some(MatchPolicy::IgnorePort),
+                            };
+                            if let Some(policy) = policy.filter(|_| parts.len() == 2 || parts.len() == 3) {
                                 if let Ok(index) = parts[1].parse::<usize>() {
                                     let maybe_entry = device_list.get(index).cloned();
                                     match maybe_entry {
                                         Some(entry) => {
-                                            let mut whitelist = WHITELIST.write().unwrap();
-                                            if whitelist.contains_key(&entry.id) {
-                                                push_log(format!("> [{}] already in whitelist", entry.id));
+                                            // Only interfaces allowed with iallow are recorded, otherwise the whole device
+                                            let approved_interfaces = if entry.allowed_interfaces.is_empty() {
+                                                None
+                                            } else {
+                                                Some(entry.allowed_interfaces.clone())
+                                            };
+                                            if whitelist_device_main(entry.identity.clone(), policy, approved_interfaces.clone()) {
+                                                push_log(format!("> Added {} to whitelist", entry.identity.describe()));
                                             } else {
-                                                whitelist.insert(entry.id.clone(), entry.name.clone());
-                                                push_log(format!("> Added [{}] {} to whitelist", entry.id, entry.name));
-                                                if let Err(e) = device_list.remove_device(index) {
-                                                    push_log(format!("> Failed to remove device [{}]: {}", entry.id, e));
-                                                }
+                                                push_log(format!("> [{}] already in whitelist", entry.identity.id()));
+                                            }
+                                            // Device waiting in the review list is authorized even when it was trusted already
+                                            if default_deny.is_enabled() {
+                                                authorize_device(&entry.id, approved_interfaces.as_ref());
+                                            }
+                                            if let Err(e) = device_list.remove_device(index) {
+                                                push_log(format!("> Failed to remove device [{}]: {}", entry.id, e));
                                             }
                                         }
                                         None => {
@@ -268,7 +392,125 @@ pub fn run_cli() -> Result<(), Box<dyn Error>> {
                                     push_log(format!("> Invalid index: {}", parts[1]));
                                 }
                             } else {
-                                push_log("> Usage: :wadd <index>".to_string());
+                                push_log("> Usage: :wadd <index> [strict|ignore-port|id-only]".to_string());
+                            }
+                        }
+
+                        cmd if cmd.starts_with("iallow ") || cmd.starts_with("iblock ") => {
+                            // Allow or block a single interface of a device from the review list
+                            let parts: Vec<&str> = cmd.split_whitespace().collect();
+                            let allow = parts[0] == "iallow";
+                            let index = parts.get(1).and_then(|value| value.parse::<usize>().ok());
+                            let number = parts.get(2).and_then(|value| value.parse::<u8>().ok());
+                            match (parts.len(), index, number) {
+                                (3, Some(index), Some(number)) => match device_list.get_mut(index) {
+                                    Some(entry) if entry.interfaces.iter().any(|interface| interface.number == number) => {
+                                        if allow {
+                                            entry.allowed_interfaces.insert(number);
+                                            authorize_device(&entry.id, Some(&entry.allowed_interfaces));
+                                            push_log(format!("> Interface {} of [{}] allowed, use wadd {} to remember it", number, entry.id, index));
+                                        } else {
+                                            entry.allowed_interfaces.remove(&number);
+                                            match set_interface_authorized(&entry.id, number, false) {
+                                                Ok(_) => push_log(format!("> Interface {} of [{}] blocked", number, entry.id)),
+                                                Err(e) => push_log(format!("> Failed to block interface {} of [{}]: {}", number, entry.id, e)),
+                                            }
+                                        }
+                                    }
+                                    Some(entry) => push_log(format!("> Device [{}] has no interface {}", entry.id, number)),
+                                    None => push_log(format!("> No device at index {}", index)),
+                                },
+                                _ => push_log("> Usage: :iallow <index> <interface> or :iblock <index> <interface>".to_string()),
+                            }
+                        }
+
+                        "blocked" => {
+                            log_quarantine(); // List all devices disabled by RustGuardian
+                        }
+
+                        cmd if cmd.starts_with("unblock ") || cmd.starts_with("forget ") => {
+                            let parts: Vec<&str> = cmd.split_whitespace().collect();
+                            match parts.get(1).and_then(|value| value.parse::<usize>().ok()) {
+                                Some(index) if parts.len() == 2 => {
+                                    let result = if parts[0] == "unblock" { unblock(index) } else { forget(index) };
+                                    match result {
+                                        Ok(entry) => push_log(format!("> Removed [{}] {} from blocked devices", entry.port_path, entry.name)),
+                                        Err(e) => push_log(format!("> {}", e)),
+                                    }
+                                }
+                                _ => push_log("> Usage: :unblock <index> or :forget <index>".to_string()),
+                            }
+                        }
+
+                        "sessions" => {
+                            // Keys of encrypted sessions are derived with PBKDF2, which must not freeze the TUI
+                            thread::spawn(log_sessions); // List keystroke sessions of monitored keyboards
+                        }
+
+                        cmd if cmd.starts_with("sessions ") => {
+                            match cmd["sessions ".len()..].trim().parse::<usize>() {
+                                Ok(index) => {
+                                    thread::spawn(move || {
+                                        if let Err(e) = show_session(index) {
+                                            push_log(format!("> {}", e));
+                                        }
+                                    });
+                                }
+                                Err(_) => push_log("> Usage: :sessions or :sessions <index>".to_string()),
+                            }
+                        }
+
+                        "scans" => {
+                            log_scans(); // List scans of mounted drives with their progress
+                        }
+
+                        cmd if cmd.starts_with("scan ") => {
+                            let parts: Vec<&str> = cmd.split_whitespace().collect();
+                            match (parts.get(1).copied(), parts.get(2).and_then(|value| value.parse::<u64>().ok())) {
+                                (Some("status"), Some(id)) if parts.len() == 3 => {
+                                    if let Err(e) = log_scan_status(id) {
+                                        push_log(format!("> {}", e));
+                                    }
+                                }
+                                (Some("cancel"), Some(id)) if parts.len() == 3 => match cancel_scan(id) {
+                                    Ok(()) => push_log(format!("> Scan #{} is being cancelled", id)),
+                                    Err(e) => push_log(format!("> {}", e)),
+                                },
+                                _ => push_log("> Usage: :scan status <id> or :scan cancel <id>".to_string()),
+                            }
+                        }
+
+                        cmd if cmd.starts_with("disconnect ") => {
+                            // Built-in device that was judged malicious, it is disconnected only when the user asks
+                            match disconnect_pending(cmd["disconnect ".len()..].trim()) {
+                                Ok(result) => push_log(format!("[ACTION] {}", result)),
+                                Err(e) => push_log(format!("> {}", e)),
+                            }
+                        }
+
+                        cmd if cmd.starts_with("feed ") => {
+                            let parts: Vec<&str> = cmd.split_whitespace().collect();
+                            match (parts.get(1).copied(), parts.get(2).copied()) {
+                                (Some("rollback"), Some(name)) if parts.len() == 3 => {
+                                    // Indexes are mapped again, which could take a moment for a big feed
+                                    let name = name.to_string();
+                                    thread::spawn(move || {
+                                        if let Err(e) = rollback_feed_main(&name) {
+                                            push_log(format!("> {}", e));
+                                        }
+                                    });
+                                }
+                                _ => push_log("> Usage: :feed rollback <name>".to_string()),
+                            }
+                        }
+
+                        "trust all" | "trust none" => {
+                            // Answer for the first run question about currently attached devices
+                            if WHITELIST_FIRST_RUN.load(Ordering::SeqCst) {
+                                finish_whitelist_setup(input.trim() == "trust all");
+                                whitelist_changed = true;
+                            } else {
+                                push_log("> Whitelist is already set up, use wadd to trust new devices".to_string());
                             }
                         }
 
@@ -286,9 +528,40 @@ pub fn run_cli() -> Result<(), Box<dyn Error>> {
                             }
                         }
                         
+                        "enable DefaultDeny" => {
+                            if std::env::var("USER").unwrap_or_default() != "root" {
+                                push_log("[SECURITY] You are not root".to_string());
+                            }
+                            else if !default_deny.is_enabled() {
+                                match default_deny.enable() {
+                                    Ok(hubs) => {
+                                        push_log(format!("[SECURITY] DefaultDeny mode enabled on {} root hubs, new devices have to be approved with wadd", hubs));
+                                    }
+                                    Err(e) => push_log(format!("[ERROR] Failed to enable DefaultDeny mode: {}", e)),
+                                }
+                            }
+                            else{
+                                push_log("[SECURITY] DefaultDeny mode was already enabled".to_string());
+                            }
+                        }
+
+                        "disable DefaultDeny" => {
+                            if default_deny.is_enabled() {
+                                match default_deny.disable() {
+                                    Ok(()) => {
+                                        push_log("[SECURITY] DefaultDeny mode disabled, devices waiting for review stay unauthorized".to_string());
+                                    }
+                                    Err(e) => push_log(format!("[ERROR] Failed to disable DefaultDeny mode: {}", e)),
+                                }
+                            }
+                            else{
+                                push_log("[SECURITY] DefaultDeny was not enabled".to_string());
+                            }
+                        }
+
                         "enable SafeConnection" => {
                             push_log("[SECURITY] SafeConnection mode enabled".to_string());
-                            safe_connection = true;
+                            media_scan_trigger.get_or_insert_with(MediaScanTrigger::start);
                         }
 
                         "disable LockDown" => {
@@ -306,7 +579,7 @@ pub fn run_cli() -> Result<(), Box<dyn Error>> {
 
                         "disable SafeConnection" => {
                             push_log("[SECURITY] SafeConnection mode disabled".to_string());
-                            safe_connection = false;
+                            media_scan_trigger = None;
                         }
 
                         _ => {
@@ -338,81 +611,36 @@ pub fn run_cli() -> Result<(), Box<dyn Error>> {
                 _ => {}
             },
 
-            Event::Tick => {
-                // Wait until whitelist has been initialized
+            Event::Usb(usb_event) => {
+                // Events are kept until whitelist has been initialized
                 if !WHITELIST_READY.load(Ordering::SeqCst) {
-                    continue;
+                    pending_usb_events.push(usb_event);
+                } else {
+                    handle_usb_event(usb_event, &mut device_list, &mut known_devices, default_deny.is_enabled());
                 }
+            }
 
-                // Poll USB devices via lsusb
-                let current_devices = create_whitelist_from_connected_devices_main();
-
-                for (id, name) in &current_devices {
-                    let whitelist = WHITELIST.read().unwrap();
-                    let id_clone = id.clone();
-                    let name_clone = name.clone();
-                    // If device is unknown, warn and add to tracked list
-                    if !whitelist.contains_key(id) && !known_devices.contains(id) {
-                        push_log(format!("[ALERT] Unknown device [{}] {} connected!", id_clone, name_clone));
-                        Notification::new()
-                            .summary("USB Device Alert")
-                            .body(&format!("Unknown device connected:\n[{}] {}", id_clone, name_clone))
-                            .icon("dialog-warning")
-                            .show()
-                            .ok(); 
-                        known_devices = current_devices.keys().cloned().collect();
-
-                        if safe_connection {
-                            thread::spawn(move || {
-                                thread::sleep(Duration::from_secs(3));
-                                push_log("[INFO] Checking the device".to_string());
-                                if let Some(mount_path) = detect_new_media_mount_main() {
-                                    push_log(format!("[USB MOUNT DETECTED] {:?}", mount_path));
-                                    let mount_path = Path::new(&mount_path);
-                                    start_hash_checker(&mount_path);   
-                                }
-
-                                
-                            });
-                        }
-
-                        let entry = DeviceEntry {
-                            id: id.clone(),
-                            name: name.clone(),
-                        };
-                        match device_list.add_device(entry) {
-                            Ok(index) => {
-                                push_log(format!("> Added device [{}] to slot {}", id, index));
-                            }
-                            Err(e) => {
-                                push_log(format!("> Failed to add device [{}]: {}", id, e));
-                            }
-                        }
+            Event::Tick => {
+                // Devices attached on startup are checked once the whitelist is initialized
+                if !startup_devices_checked && WHITELIST_READY.load(Ordering::SeqCst) {
+                    startup_devices_checked = true;
+                    for identity in list_connected_devices_main() {
+                        handle_usb_event(UsbEvent::Added(identity), &mut device_list, &mut known_devices, default_deny.is_enabled());
                     }
-                }
-
-                // Remove disconnected devices from device_list
-                let current_ids: HashSet<_> = current_devices.keys().cloned().collect();
-                let removed_ids: Vec<String> = known_devices
-                    .difference(&current_ids)
-                    .cloned()
-                    .collect();
-
-                for id in removed_ids {
-                    // Try to find and remove device from device_list
-                    if let Some(index) = (0..100).find(|&i| device_list.get(i).map(|d| &d.id) == Some(&id)) {
-                        if let Err(e) = device_list.remove_device(index) {
-                            push_log(format!("> Failed to remove disconnected device [{}]: {}", id, e));
-                        } else {
-                            push_log(format!("> Device [{}] disconnected and removed from review device list", id));
-                        }
+                    for usb_event in pending_usb_events.drain(..) {
+                        handle_usb_event(usb_event, &mut device_list, &mut known_devices, default_deny.is_enabled());
                     }
                 }
-        
             }
         }
     }
 
+    // Esc and :q leave the same way
+    if lockdown {
+        device_monitor.stop();
+    }
+    // USB authorization is restored when default_deny is dropped, after the terminal
+
     // Restore terminal on exit
     disable_raw_mode()?;
     execute!(
@@ -1,10 +1,17 @@
 use crate::push_log;
+use crate::engine::whitelist::{DeviceIdentity, UsbInterface};
+use crate::engine::usb_auth::is_interface_authorized;
+use std::collections::BTreeSet;
 
 #[derive(Clone)]
-// Represents a single USB device with a unique ID and its name
+// Represents a single USB device with a unique ID and its identity
+// ID is the port path of the device, so two identical devices get separate entries
 pub struct DeviceEntry {
     pub id: String,
-    pub name: String,
+    pub identity: DeviceIdentity,
+    pub interfaces: Vec<UsbInterface>,
+    // Interfaces approved with iallow, empty means the device is approved as a whole
+    pub allowed_interfaces: BTreeSet<u8>,
 }
 
 pub struct DeviceList {
@@ -26,7 +33,19 @@ impl DeviceList {
         let mut any_found = false;
         for (i, entry) in self.devices.iter().enumerate() {
             if let Some(device) = entry {
-                push_log(format!("{}: [{}] {}", i, device.id, device.name));
+                push_log(format!("{}: {}", i, device.identity.describe()));
+                for interface in &device.interfaces {
+                    let state = if is_interface_authorized(&device.id, interface.number) { "allowed" } else { "blocked" };
+                    push_log(format!(
+                        "    interface {}: {} (class {:02x}, subclass {:02x}, protocol {:02x}) {}",
+                        interface.number,
+                        interface.class_name(),
+                        interface.class,
+                        interface.subclass,
+                        interface.protocol,
+                        state
+                    ));
+                }
                 any_found = true;
             }
         }
@@ -67,6 +86,11 @@ impl DeviceList {
         self.devices.get(index).and_then(|opt| opt.as_ref())
     }
 
+    // Returns a mutable reference to a device entry by index, if it exists
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut DeviceEntry> {
+        self.devices.get_mut(index).and_then(|opt| opt.as_mut())
+    }
+
     // Checks whether a device with the given ID is already present
     pub fn contains_id(&self, id: &str) -> bool {
         self.devices.iter().flatten().any(|d| d.id == id)
@@ -0,0 +1,58 @@
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use crate::engine::log_crypto::LogKeySource;
+use crate::engine::session_log::read_session;
+
+// Passphrase is taken from this variable before it is asked for, so logs can be read from scripts
+const PASSPHRASE_VARIABLE: &str = "RUSTGUARDIAN_LOG_PASSPHRASE";
+
+pub const LOGVIEW_USAGE: &str = "usage: main logview <session file> [--key-file <path>] [--passphrase]";
+
+// Reads a line from the terminal without showing it
+fn prompt_passphrase() -> io::Result<String> {
+    eprint!("Passphrase: ");
+    io::stderr().flush()?;
+    let mut saved = unsafe { std::mem::zeroed::<libc::termios>() };
+    let echo_disabled = unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } == 0 && {
+        let mut silent = saved;
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent) == 0 }
+    };
+    let mut passphrase = String::new();
+    let result = io::stdin().lock().read_line(&mut passphrase);
+    if echo_disabled {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &saved) };
+        eprintln!();
+    }
+    result?;
+    Ok(passphrase.trim_end_matches(['\n', '\r']).to_string())
+}
+
+/// Prints a session log to stdout, encrypted logs are decrypted with the key from the arguments or the config
+pub fn run_logview(args: &[String]) -> Result<(), String> {
+    let mut file: Option<PathBuf> = None;
+    let mut key_source = LogKeySource::configured();
+    let mut arguments = args.iter();
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--key-file" => {
+                let path = arguments.next().ok_or(LOGVIEW_USAGE)?;
+                key_source = LogKeySource::KeyFile(PathBuf::from(path));
+            }
+            "--passphrase" => {
+                let passphrase = match env::var(PASSPHRASE_VARIABLE) {
+                    Ok(passphrase) if !passphrase.is_empty() => passphrase,
+                    _ => prompt_passphrase().map_err(|e| format!("Failed to read the passphrase: {}", e))?,
+                };
+                key_source = LogKeySource::Passphrase(passphrase);
+            }
+            _ if file.is_none() && !argument.starts_with("--") => file = Some(PathBuf::from(argument)),
+            _ => return Err(LOGVIEW_USAGE.to_string()),
+        }
+    }
+    let file = file.ok_or(LOGVIEW_USAGE)?;
+    let content = read_session(Path::new(&file), &key_source).map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+    print!("{}", content);
+    Ok(())
+}
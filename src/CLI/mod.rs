@@ -1,2 +1,3 @@
 pub mod cli;
-pub mod device_functions;
\ No newline at end of file
+pub mod device_functions;
+pub mod logview;
\ No newline at end of file
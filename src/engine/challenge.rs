@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+use super::keymap::DecodedKey;
+
+// Characters of the code, letters that move between layouts (y, z) or look alike (i, l, o) are left out
+const CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwx";
+
+/// State of the challenge after a typed key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChallengeState {
+    /// Code was not confirmed with enter yet
+    Typing,
+    Passed,
+    /// Wrong code was confirmed, the number of attempts left
+    WrongCode { attempts_left: usize },
+    /// All attempts were used
+    Failed,
+}
+
+/// Random code that has to be typed on a new keyboard to prove a human is behind it.
+/// A BadUSB payload does not know the code, it is shown only on the screen of this computer
+pub struct Challenge {
+    code: String,
+    typed: String,
+    attempts_left: usize,
+    expires: Instant,
+}
+
+impl Challenge {
+    /// Generates the code from /dev/urandom, it has to be typed within the timeout
+    pub fn generate(length: usize, attempts: usize, timeout: Duration) -> io::Result<Self> {
+        let mut urandom = File::open("/dev/urandom")?;
+        let mut code = String::with_capacity(length);
+        let mut byte = [0u8; 1];
+        // Bytes above the last whole multiple of the alphabet are skipped, so every character is equally likely
+        let limit = 256 - 256 % CODE_ALPHABET.len();
+        while code.len() < length {
+            urandom.read_exact(&mut byte)?;
+            if (byte[0] as usize) < limit {
+                code.push(CODE_ALPHABET[byte[0] as usize % CODE_ALPHABET.len()] as char);
+            }
+        }
+        Ok(Challenge { code, typed: String::new(), attempts_left: attempts.max(1), expires: Instant::now() + timeout })
+    }
+
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    /// The code was not typed in time
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires
+    }
+
+    /// Adds the decoded key, the code is checked when enter is pressed
+    pub fn key(&mut self, key: &DecodedKey) -> ChallengeState {
+        match key {
+            DecodedKey::Char(c) => {
+                // Nobody types a code this long, it does not have to be kept
+                if self.typed.chars().count() < self.code.len() * 4 {
+                    self.typed.push(*c);
+                }
+                ChallengeState::Typing
+            }
+            DecodedKey::Backspace => {
+                self.typed.pop();
+                ChallengeState::Typing
+            }
+            DecodedKey::Token(_) => ChallengeState::Typing,
+            DecodedKey::Enter => {
+                // Caps lock should not fail the code
+                let typed = std::mem::take(&mut self.typed).trim().to_lowercase();
+                if typed == self.code {
+                    return ChallengeState::Passed;
+                }
+                self.attempts_left = self.attempts_left.saturating_sub(1);
+                if self.attempts_left == 0 {
+                    ChallengeState::Failed
+                } else {
+                    ChallengeState::WrongCode { attempts_left: self.attempts_left }
+                }
+            }
+        }
+    }
+}
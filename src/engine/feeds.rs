@@ -0,0 +1,862 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use flate2::read::MultiGzDecoder;
+use lazy_static::lazy_static;
+use reqwest::blocking::get;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zip::read::ZipArchive;
+use crate::push_log;
+use super::hash_index::{parse_hex_digest, HashIndex};
+use super::storage::{invalid_data, write_atomically};
+
+pub const FEEDS_FILE_VERSION: u32 = 1;
+
+// Feeds are checked for a due refresh this often while RustGuardian runs
+const REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+lazy_static! {
+    // Copies of the feeds are changed by the refresh thread and by the rollback command
+    static ref FEEDS_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Held while the feed copies are updated or rolled back and their signatures installed,
+/// so a rollback never runs in the middle of an update
+pub fn lock_feeds() -> MutexGuard<'static, ()> {
+    FEEDS_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Hash algorithm of the digests a feed lists
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Length of the raw digest in bytes
+    pub fn digest_length(&self) -> usize {
+        match self {
+            HashAlgorithm::Md5 => 16,
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 => 32,
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HashAlgorithm::Md5 => write!(f, "MD5"),
+            HashAlgorithm::Sha1 => write!(f, "SHA-1"),
+            HashAlgorithm::Sha256 => write!(f, "SHA-256"),
+        }
+    }
+}
+
+/// How the hashes are written in the feed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedFormat {
+    /// One hash per line, anything after the hash on the line is ignored
+    #[default]
+    Plain,
+    /// Hash is in the column of the feed
+    Csv,
+}
+
+/// Archive the feed is downloaded in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedWrapping {
+    #[default]
+    None,
+    Zip,
+    Gzip,
+}
+
+/// Source of malware hashes from feeds.json
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Feed {
+    /// Reported with every file the feed flags, it also names the local copy of the feed
+    pub name: String,
+    /// http(s) URL or file:// path of a local list
+    pub url: String,
+    #[serde(default)]
+    pub format: FeedFormat,
+    /// Column of the hash in a CSV feed, counted from 0
+    #[serde(default)]
+    pub column: usize,
+    #[serde(default = "default_delimiter")]
+    pub delimiter: char,
+    #[serde(default)]
+    pub wrapping: FeedWrapping,
+    pub algorithm: HashAlgorithm,
+    /// Local copy older than this is fetched again
+    #[serde(default = "default_refresh_hours")]
+    pub refresh_hours: u64,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// File with the SHA-256 of the download published next to the feed, the download is checked against it
+    #[serde(default)]
+    pub checksum_url: Option<String>,
+    /// Downloads without a published checksum are rejected unless this is set
+    #[serde(default)]
+    pub allow_unverified: bool,
+    /// Export of the lately added hashes, merged into the local copy instead of downloading the whole feed
+    #[serde(default)]
+    pub recent: Option<RecentFeed>,
+    /// Whole feed is downloaded again after this time even with recent exports, so removed hashes are dropped
+    #[serde(default = "default_full_refresh_hours")]
+    pub full_refresh_hours: u64,
+}
+
+/// Delta export of a feed, in the same format and with the same algorithm as the feed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecentFeed {
+    pub url: String,
+    #[serde(default)]
+    pub wrapping: FeedWrapping,
+    #[serde(default)]
+    pub checksum_url: Option<String>,
+    /// Export lists the hashes added in this many last hours, an older copy needs the whole feed
+    #[serde(default = "default_refresh_hours")]
+    pub covers_hours: u64,
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+fn default_refresh_hours() -> u64 {
+    48
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_full_refresh_hours() -> u64 {
+    7 * 24
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+// When the feed was fetched, stored in feeds/<name>.state.json next to the copy
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct FeedState {
+    /// Unix time of the last download of the whole feed
+    full_at: u64,
+    /// Unix time of the last download of the feed or its recent export
+    updated_at: u64,
+    /// Copy was rolled back, the recent export is not merged into it until the whole feed is downloaded again
+    #[serde(default)]
+    rolled_back: bool,
+}
+
+impl Feed {
+    fn local_copy(&self, directory: &Path) -> PathBuf {
+        directory.join(format!("{}.txt", self.name))
+    }
+
+    /// Copy replaced by the last update, kept for a rollback
+    fn previous_copy(&self, directory: &Path) -> PathBuf {
+        directory.join(format!("{}.txt.prev", self.name))
+    }
+
+    fn index(&self, directory: &Path) -> PathBuf {
+        directory.join(format!("{}.idx", self.name))
+    }
+
+    fn state_path(&self, directory: &Path) -> PathBuf {
+        directory.join(format!("{}.state.json", self.name))
+    }
+
+    // Copies without a state are from before the state was kept, their modification time is the last download
+    fn state(&self, directory: &Path) -> Option<FeedState> {
+        if let Some(state) = fs::read_to_string(self.state_path(directory)).ok().and_then(|data| serde_json::from_str(&data).ok()) {
+            return Some(state);
+        }
+        let modified = fs::metadata(self.local_copy(directory)).and_then(|metadata| metadata.modified()).ok()?;
+        let modified = modified.duration_since(SystemTime::UNIX_EPOCH).map(|modified| modified.as_secs()).unwrap_or(0);
+        Some(FeedState { full_at: modified, updated_at: modified, rolled_back: false })
+    }
+
+    fn save_state(&self, directory: &Path, state: FeedState) -> io::Result<()> {
+        let data = serde_json::to_string_pretty(&state).map_err(invalid_data)?;
+        write_atomically(&self.state_path(directory), data.as_bytes(), 0o644)
+    }
+
+    /// Local copy is missing or was fetched longer than the refresh interval ago
+    pub fn is_due(&self, directory: &Path) -> bool {
+        match self.state(directory) {
+            Some(state) if self.local_copy(directory).exists() => unix_now().saturating_sub(state.updated_at) > self.refresh_hours * 3600,
+            _ => true,
+        }
+    }
+
+    // Takes the hash out of a line of the feed, None for comments and headers
+    fn hash_of_line<'a>(&self, line: &'a str) -> Option<&'a str> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let hash = match self.format {
+            FeedFormat::Plain => line.split_whitespace().next()?,
+            FeedFormat::Csv => line.split(self.delimiter).nth(self.column)?.trim().trim_matches('"').trim(),
+        };
+        parse_hex_digest(hash, self.algorithm.digest_length()).map(|_| hash)
+    }
+
+    // Lowercase hashes the downloaded feed lists
+    fn hashes_of(&self, data: &[u8]) -> Vec<String> {
+        String::from_utf8_lossy(data)
+            .lines()
+            .filter_map(|line| self.hash_of_line(line))
+            .map(|hash| hash.to_ascii_lowercase())
+            .collect()
+    }
+}
+
+/// Name of the default feed, older versions downloaded it into hashes.txt
+pub const DEFAULT_FEED_NAME: &str = "malwarebazaar-sha256";
+
+/// Registry used when there is no feeds.json, the full SHA-256 export of MalwareBazaar
+pub fn default_feeds() -> Vec<Feed> {
+    vec![Feed {
+        name: DEFAULT_FEED_NAME.to_string(),
+        url: "https://bazaar.abuse.ch/export/txt/sha256/full/".to_string(),
+        format: FeedFormat::Plain,
+        column: 0,
+        delimiter: default_delimiter(),
+        wrapping: FeedWrapping::Zip,
+        algorithm: HashAlgorithm::Sha256,
+        // Recent export is small, so it is fetched more often than the whole feed would be
+        refresh_hours: 12,
+        enabled: true,
+        // MalwareBazaar publishes no checksum of its exports, feeds.json has to allow it unverified
+        checksum_url: None,
+        allow_unverified: false,
+        recent: Some(RecentFeed {
+            url: "https://bazaar.abuse.ch/export/txt/sha256/recent/".to_string(),
+            wrapping: FeedWrapping::None,
+            checksum_url: None,
+            covers_hours: 48,
+        }),
+        full_refresh_hours: default_full_refresh_hours(),
+    }]
+}
+
+// Feed registry is stored in the project root next to whitelist.json
+pub fn feeds_file_path() -> String {
+    format!("{}/feeds.json", env!("CARGO_MANIFEST_DIR"))
+}
+
+/// Local copies of the feeds and their indexes
+pub fn feeds_directory() -> PathBuf {
+    PathBuf::from(format!("{}/feeds", env!("CARGO_MANIFEST_DIR")))
+}
+
+/// Hashes downloaded by versions before the feed registry
+pub fn legacy_hashes_path() -> PathBuf {
+    PathBuf::from(format!("{}/hashes.txt", env!("CARGO_MANIFEST_DIR")))
+}
+
+#[derive(Serialize, Deserialize)]
+struct FeedsFile {
+    version: u32,
+    feeds: Vec<Feed>,
+}
+
+/// Loads the feed registry. Returns `Ok(None)` when there is no feeds.json
+pub fn load_feeds(path: &Path) -> io::Result<Option<Vec<Feed>>> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let file: FeedsFile = serde_json::from_str(&data).map_err(invalid_data)?;
+    if file.version != FEEDS_FILE_VERSION {
+        return Err(invalid_data(format!("unsupported feeds version {}", file.version)));
+    }
+    for (i, feed) in file.feeds.iter().enumerate() {
+        // Name becomes a file name in the feeds directory
+        let valid_name = !feed.name.is_empty()
+            && !feed.name.starts_with('.')
+            && feed.name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid_name {
+            return Err(invalid_data(format!("feed name {:?} may only have letters, digits, '-', '_' and '.'", feed.name)));
+        }
+        if file.feeds[..i].iter().any(|other| other.name == feed.name) {
+            return Err(invalid_data(format!("feed {} is listed twice", feed.name)));
+        }
+        let recent = feed.recent.as_ref();
+        let urls = [Some(&feed.url), feed.checksum_url.as_ref(), recent.map(|recent| &recent.url), recent.and_then(|recent| recent.checksum_url.as_ref())];
+        for url in urls.into_iter().flatten() {
+            if !["http://", "https://", "file://"].iter().any(|scheme| url.starts_with(scheme)) {
+                return Err(invalid_data(format!("feed {} has {} which is no http(s):// or file:// URL", feed.name, url)));
+            }
+        }
+    }
+    Ok(Some(file.feeds))
+}
+
+fn fetch(url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    match url.strip_prefix("file://") {
+        Some(path) => Ok(fs::read(path)?),
+        None => Ok(get(url)?.error_for_status()?.bytes()?.to_vec()),
+    }
+}
+
+// Unpacks the downloaded feed, all files of a ZIP archive are joined
+fn unwrap(data: Vec<u8>, wrapping: FeedWrapping) -> io::Result<Vec<u8>> {
+    match wrapping {
+        FeedWrapping::None => Ok(data),
+        FeedWrapping::Gzip => {
+            let mut unpacked = Vec::new();
+            MultiGzDecoder::new(data.as_slice()).read_to_end(&mut unpacked)?;
+            Ok(unpacked)
+        }
+        FeedWrapping::Zip => {
+            let mut archive = ZipArchive::new(Cursor::new(data)).map_err(invalid_data)?;
+            if archive.is_empty() {
+                return Err(invalid_data("ZIP archive is empty"));
+            }
+            let mut unpacked = Vec::new();
+            for i in 0..archive.len() {
+                let mut file = archive.by_index(i).map_err(invalid_data)?;
+                if file.is_file() {
+                    file.read_to_end(&mut unpacked)?;
+                    unpacked.push(b'\n');
+                }
+            }
+            Ok(unpacked)
+        }
+    }
+}
+
+// Published checksum files have the SHA-256 as the first hash, usually followed by the file name
+fn verify_checksum(data: &[u8], published: &[u8]) -> Result<(), String> {
+    let published = String::from_utf8_lossy(published);
+    let expected = published
+        .split_whitespace()
+        .find_map(|word| parse_hex_digest(word, HashAlgorithm::Sha256.digest_length()))
+        .ok_or("published checksum has no SHA-256 hash")?;
+    if Sha256::digest(data).as_slice() != expected.as_slice() {
+        return Err("download does not match the published checksum".to_string());
+    }
+    Ok(())
+}
+
+// Fetches a download of the feed, verifies it against the published checksum and unpacks it
+fn download(feed: &Feed, url: &str, checksum_url: Option<&String>, wrapping: FeedWrapping) -> Result<Vec<u8>, Box<dyn Error>> {
+    if checksum_url.is_none() && !feed.allow_unverified {
+        return Err(format!("{} has no checksum_url, set allow_unverified in feeds.json to use it unverified", url).into());
+    }
+    let data = fetch(url)?;
+    match checksum_url {
+        Some(checksum_url) => verify_checksum(&data, &fetch(checksum_url)?)?,
+        None => push_log(format!("[WARNING] Feed {} publishes no checksum, {} is not verified", feed.name, url)),
+    }
+    Ok(unwrap(data, wrapping)?)
+}
+
+// New copy is written to a temporary file and renamed over the old one, the old one stays as the previous version
+fn install_copy(feed: &Feed, directory: &Path, hashes: &str) -> io::Result<()> {
+    fs::create_dir_all(directory)?;
+    let copy = feed.local_copy(directory);
+    if copy.exists() {
+        let previous = feed.previous_copy(directory);
+        let mut tmp_name = previous.as_os_str().to_owned();
+        tmp_name.push(".tmp");
+        let _ = fs::remove_file(&tmp_name);
+        fs::hard_link(&copy, &tmp_name)?;
+        fs::rename(&tmp_name, &previous)?;
+    }
+    write_atomically(&copy, hashes.as_bytes(), 0o644)
+}
+
+/// What an update of a feed did
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeedUpdate {
+    /// Whole feed was downloaded
+    Full { hashes: usize },
+    /// Recent export was merged into the copy
+    Recent { added: usize, hashes: usize },
+}
+
+fn update_from_recent(feed: &Feed, recent: &RecentFeed, directory: &Path) -> Result<FeedUpdate, Box<dyn Error>> {
+    let new_hashes = feed.hashes_of(&download(feed, &recent.url, recent.checksum_url.as_ref(), recent.wrapping)?);
+    let current = fs::read_to_string(feed.local_copy(directory))?;
+    let mut merged = current.clone();
+    if !merged.is_empty() && !merged.ends_with('\n') {
+        merged.push('\n');
+    }
+    let mut known: HashSet<&str> = current.lines().collect();
+    let mut added = 0;
+    for hash in &new_hashes {
+        if known.insert(hash) {
+            merged.push_str(hash);
+            merged.push('\n');
+            added += 1;
+        }
+    }
+    // Nothing new keeps the copy, so the previous version is not lost for nothing
+    if added > 0 {
+        install_copy(feed, directory, &merged)?;
+    }
+    Ok(FeedUpdate::Recent { added, hashes: known.len() })
+}
+
+fn update_from_full(feed: &Feed, directory: &Path) -> Result<FeedUpdate, Box<dyn Error>> {
+    let hashes = feed.hashes_of(&download(feed, &feed.url, feed.checksum_url.as_ref(), feed.wrapping)?);
+    // Error page or a wrong column would leave the feed empty, the old copy is kept instead
+    if hashes.is_empty() {
+        return Err(format!("no {} hashes were found in the feed", feed.algorithm).into());
+    }
+    let mut content = hashes.join("\n");
+    content.push('\n');
+    install_copy(feed, directory, &content)?;
+    Ok(FeedUpdate::Full { hashes: hashes.len() })
+}
+
+/// Brings the local copy of the feed up to date, one lowercase hex digest per line.
+/// The recent export is merged when the copy is new enough for it, otherwise the whole feed is downloaded
+pub fn update_feed(feed: &Feed, directory: &Path) -> Result<FeedUpdate, Box<dyn Error>> {
+    let now = unix_now();
+    let state = feed.state(directory).filter(|_| feed.local_copy(directory).exists());
+    if let (Some(recent), Some(state)) = (&feed.recent, state) {
+        let covered = now.saturating_sub(state.updated_at) < recent.covers_hours * 3600;
+        let full_is_fresh = now.saturating_sub(state.full_at) < feed.full_refresh_hours * 3600;
+        if covered && full_is_fresh && !state.rolled_back {
+            match update_from_recent(feed, recent, directory) {
+                Ok(update) => {
+                    feed.save_state(directory, FeedState { updated_at: now, ..state })?;
+                    return Ok(update);
+                }
+                Err(e) => push_log(format!("[WARNING] Recent hashes of feed {} could not be merged: {}. Downloading the whole feed", feed.name, e)),
+            }
+        }
+    }
+    let update = update_from_full(feed, directory)?;
+    feed.save_state(directory, FeedState { full_at: now, updated_at: now, rolled_back: false })?;
+    Ok(update)
+}
+
+/// Imports the hashes.txt of an older version as the copy of the default feed when it has none yet,
+/// so an upgrade without network keeps its signatures. Returns the number of imported hashes
+pub fn import_legacy_hashes(feeds: &[Feed], directory: &Path, legacy: &Path) -> io::Result<usize> {
+    let Some(feed) = feeds.iter().find(|feed| feed.name == DEFAULT_FEED_NAME) else {
+        return Ok(0);
+    };
+    if feed.local_copy(directory).exists() {
+        return Ok(0);
+    }
+    let data = match fs::read(legacy) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let hashes = feed.hashes_of(&data);
+    if hashes.is_empty() {
+        return Ok(0);
+    }
+    // Old file is as fresh as its last download, which decides if the recent export can bring it up to date
+    let modified = fs::metadata(legacy)?.modified()?;
+    let modified = modified.duration_since(SystemTime::UNIX_EPOCH).map(|modified| modified.as_secs()).unwrap_or(0);
+    let mut content = hashes.join("\n");
+    content.push('\n');
+    install_copy(feed, directory, &content)?;
+    feed.save_state(directory, FeedState { full_at: modified, updated_at: modified, rolled_back: false })?;
+    Ok(hashes.len())
+}
+
+/// Puts back the copy that the last update replaced. The replaced copy becomes the previous one,
+/// so the rollback can be undone with another rollback. The next update downloads the whole feed,
+/// the recent export that was rolled back would be merged again otherwise
+pub fn rollback_feed(feed: &Feed, directory: &Path) -> io::Result<()> {
+    let copy = feed.local_copy(directory);
+    let previous = feed.previous_copy(directory);
+    if !previous.exists() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("feed {} has no previous version", feed.name)));
+    }
+    let mut tmp_name = copy.as_os_str().to_owned();
+    tmp_name.push(".rollback");
+    let _ = fs::remove_file(&tmp_name);
+    fs::hard_link(&copy, &tmp_name)?;
+    fs::rename(&previous, &copy)?;
+    fs::rename(&tmp_name, &previous)?;
+    let state = feed.state(directory).unwrap_or_default();
+    feed.save_state(directory, FeedState { rolled_back: true, ..state })
+}
+
+/// Hashes of one file, computed only with the algorithms of the loaded feeds
+#[derive(Clone, Debug, Default)]
+pub struct FileDigests(pub Vec<(HashAlgorithm, Vec<u8>)>);
+
+impl FileDigests {
+    pub fn get(&self, algorithm: HashAlgorithm) -> Option<&[u8]> {
+        self.0.iter().find(|(used, _)| *used == algorithm).map(|(_, digest)| digest.as_slice())
+    }
+}
+
+/// Feed whose index is mapped and used by scans
+pub struct LoadedFeed {
+    pub name: String,
+    pub algorithm: HashAlgorithm,
+    pub index: HashIndex,
+}
+
+/// Indexes of all loaded feeds
+#[derive(Default)]
+pub struct SignatureSet {
+    pub feeds: Vec<LoadedFeed>,
+}
+
+impl SignatureSet {
+    /// Algorithms a file has to be hashed with, each of them once
+    pub fn algorithms(&self) -> Vec<HashAlgorithm> {
+        let mut algorithms: Vec<HashAlgorithm> = self.feeds.iter().map(|feed| feed.algorithm).collect();
+        algorithms.sort();
+        algorithms.dedup();
+        algorithms
+    }
+
+    /// Names of the feeds that list the file
+    pub fn matching_feeds(&self, digests: &FileDigests) -> Vec<String> {
+        self.feeds
+            .iter()
+            .filter(|feed| digests.get(feed.algorithm).is_some_and(|digest| feed.index.contains(digest)))
+            .map(|feed| feed.name.clone())
+            .collect()
+    }
+
+    /// Adds digests to the first feed of the algorithm
+    pub fn with_extra(mut self, algorithm: HashAlgorithm, hex_digests: &[&str]) -> Self {
+        if let Some(position) = self.feeds.iter().position(|feed| feed.algorithm == algorithm) {
+            let feed = self.feeds.remove(position);
+            self.feeds.insert(position, LoadedFeed { index: feed.index.with_extra(hex_digests), ..feed });
+        }
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.feeds.iter().map(|feed| feed.index.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Maps the indexes of all enabled feeds that have a local copy
+pub fn load_signatures(feeds: &[Feed], directory: &Path) -> SignatureSet {
+    let mut signatures = SignatureSet::default();
+    for feed in feeds.iter().filter(|feed| feed.enabled && feed.local_copy(directory).exists()) {
+        match HashIndex::load_or_build(&feed.local_copy(directory), &feed.index(directory), feed.algorithm.digest_length()) {
+            Ok(index) => {
+                push_log(format!("[INFO] Loaded {} {} hashes of feed {}", index.len(), feed.algorithm, feed.name));
+                signatures.feeds.push(LoadedFeed { name: feed.name.clone(), algorithm: feed.algorithm, index });
+            }
+            Err(e) => push_log(format!("[ERROR] Hash index of feed {} could not be loaded: {}", feed.name, e)),
+        }
+    }
+    signatures
+}
+
+/// Updates the feeds that are due and maps the indexes of all enabled feeds.
+/// A feed that could not be updated is used from its old copy, when there is one
+pub fn refresh_feeds(feeds: &[Feed], directory: &Path) -> SignatureSet {
+    for feed in feeds.iter().filter(|feed| feed.enabled && feed.is_due(directory)) {
+        match update_feed(feed, directory) {
+            Ok(FeedUpdate::Full { hashes }) => push_log(format!("[INFO] Feed {} was downloaded with {} {} hashes", feed.name, hashes, feed.algorithm)),
+            Ok(FeedUpdate::Recent { added, hashes }) => {
+                push_log(format!("[INFO] {} recent hashes were merged into feed {}, it has {} hashes", added, feed.name, hashes))
+            }
+            Err(e) if feed.local_copy(directory).exists() => push_log(format!(
+                "[WARNING] Feed {} could not be updated: {}. RustGuardian will continue working with the outdated hashes for now",
+                feed.name, e
+            )),
+            Err(e) => push_log(format!("[ERROR] Feed {} could not be downloaded: {}", feed.name, e)),
+        }
+    }
+    load_signatures(feeds, directory)
+}
+
+/// Checks the feeds in the background and passes the new signatures to install when a feed was due
+pub fn start_feed_refresh(feeds: Vec<Feed>, running: Arc<AtomicBool>, install: fn(SignatureSet)) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let directory = feeds_directory();
+        while running.load(Ordering::Relaxed) {
+            let mut waited = Duration::ZERO;
+            while waited < REFRESH_CHECK_INTERVAL && running.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_secs(1));
+                waited += Duration::from_secs(1);
+            }
+            if running.load(Ordering::Relaxed) && feeds.iter().any(|feed| feed.enabled && feed.is_due(&directory)) {
+                let _feeds = lock_feeds();
+                install(refresh_feeds(&feeds, &directory));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+
+    // Local stand-in for a feed server, serves the files it was given and records every requested path
+    struct FeedServer {
+        address: String,
+        files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FeedServer {
+        fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = format!("http://{}", listener.local_addr().unwrap());
+            let files = Arc::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let (served_files, served_requests) = (files.clone(), requests.clone());
+            thread::spawn(move || {
+                for mut stream in listener.incoming().filter_map(Result::ok) {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut request_line = String::new();
+                    reader.read_line(&mut request_line).unwrap();
+                    // Headers are read up to the empty line
+                    let mut header = String::new();
+                    while reader.read_line(&mut header).unwrap() > 0 && header != "\r\n" {
+                        header.clear();
+                    }
+                    let path = request_line.split_whitespace().nth(1).unwrap_or("/").to_string();
+                    served_requests.lock().unwrap().push(path.clone());
+                    let (status, body) = match served_files.lock().unwrap().get(&path) {
+                        Some(body) => ("200 OK", body.clone()),
+                        None => ("404 Not Found", b"not found".to_vec()),
+                    };
+                    write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len()).unwrap();
+                    stream.write_all(&body).unwrap();
+                }
+            });
+            FeedServer { address, files, requests }
+        }
+
+        fn url(&self, path: &str) -> String {
+            format!("{}{}", self.address, path)
+        }
+
+        fn serve(&self, path: &str, body: &[u8]) {
+            self.files.lock().unwrap().insert(path.to_string(), body.to_vec());
+        }
+
+        // Serves the file together with its published checksum
+        fn serve_verified(&self, path: &str, body: &[u8]) {
+            self.serve(path, body);
+            self.serve(&format!("{}.sha256", path), format!("{:x}  {}\n", Sha256::digest(body), path).as_bytes());
+        }
+
+        fn requests_of(&self, path: &str) -> usize {
+            self.requests.lock().unwrap().iter().filter(|requested| *requested == path).count()
+        }
+    }
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("rustguardian-feeds-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn hash(byte: u8) -> String {
+        format!("{:02x}", byte).repeat(32)
+    }
+
+    fn hash_list(bytes: &[u8]) -> Vec<u8> {
+        bytes.iter().map(|byte| format!("{}\n", hash(*byte))).collect::<String>().into_bytes()
+    }
+
+    fn test_feed(server: &FeedServer, with_recent: bool) -> Feed {
+        Feed {
+            name: "test".to_string(),
+            url: server.url("/full.txt"),
+            format: FeedFormat::Plain,
+            column: 0,
+            delimiter: ',',
+            wrapping: FeedWrapping::None,
+            algorithm: HashAlgorithm::Sha256,
+            refresh_hours: 1,
+            enabled: true,
+            checksum_url: Some(server.url("/full.txt.sha256")),
+            allow_unverified: false,
+            recent: with_recent.then(|| RecentFeed {
+                url: server.url("/recent.txt"),
+                wrapping: FeedWrapping::None,
+                checksum_url: Some(server.url("/recent.txt.sha256")),
+                covers_hours: 48,
+            }),
+            full_refresh_hours: default_full_refresh_hours(),
+        }
+    }
+
+    fn is_flagged(signatures: &SignatureSet, byte: u8) -> bool {
+        let digest = parse_hex_digest(&hash(byte), 32).unwrap();
+        !signatures.matching_feeds(&FileDigests(vec![(HashAlgorithm::Sha256, digest)])).is_empty()
+    }
+
+    #[test]
+    fn verified_download_is_indexed() {
+        let server = FeedServer::start();
+        let directory = test_directory("verified");
+        let feed = test_feed(&server, false);
+        server.serve_verified("/full.txt", &hash_list(&[1, 2]));
+
+        assert_eq!(update_feed(&feed, &directory).unwrap(), FeedUpdate::Full { hashes: 2 });
+        assert!(!feed.is_due(&directory));
+        let signatures = load_signatures(&[feed], &directory);
+        assert!(is_flagged(&signatures, 1));
+        assert!(!is_flagged(&signatures, 3));
+    }
+
+    #[test]
+    fn checksum_mismatch_keeps_the_copy() {
+        let server = FeedServer::start();
+        let directory = test_directory("mismatch");
+        let feed = test_feed(&server, false);
+        server.serve_verified("/full.txt", &hash_list(&[1]));
+        update_feed(&feed, &directory).unwrap();
+
+        // Feed changed but the published checksum is still the one of the old download
+        server.serve("/full.txt", &hash_list(&[1, 2]));
+        assert!(update_feed(&feed, &directory).is_err());
+        assert_eq!(fs::read(feed.local_copy(&directory)).unwrap(), hash_list(&[1]));
+        assert!(!feed.previous_copy(&directory).exists());
+    }
+
+    #[test]
+    fn feed_without_checksum_is_rejected_unless_allowed() {
+        let server = FeedServer::start();
+        let directory = test_directory("unverified");
+        server.serve("/full.txt", &hash_list(&[1]));
+        let json = format!(r#"{{"name": "test", "url": "{}", "algorithm": "sha256"}}"#, server.url("/full.txt"));
+        let mut feed: Feed = serde_json::from_str(&json).unwrap();
+
+        assert!(update_feed(&feed, &directory).is_err());
+        assert_eq!(server.requests_of("/full.txt"), 0);
+        assert!(!feed.local_copy(&directory).exists());
+
+        feed.allow_unverified = true;
+        assert_eq!(update_feed(&feed, &directory).unwrap(), FeedUpdate::Full { hashes: 1 });
+    }
+
+    #[test]
+    fn recent_hashes_are_merged_and_rolled_back() {
+        let server = FeedServer::start();
+        let directory = test_directory("recent");
+        let feed = test_feed(&server, true);
+        server.serve_verified("/full.txt", &hash_list(&[1, 2]));
+        update_feed(&feed, &directory).unwrap();
+        assert_eq!(server.requests_of("/recent.txt"), 0);
+
+        server.serve_verified("/recent.txt", &hash_list(&[2, 3]));
+        assert_eq!(update_feed(&feed, &directory).unwrap(), FeedUpdate::Recent { added: 1, hashes: 3 });
+        assert_eq!(server.requests_of("/full.txt"), 1);
+        assert!(is_flagged(&load_signatures(std::slice::from_ref(&feed), &directory), 3));
+        assert_eq!(fs::read(feed.previous_copy(&directory)).unwrap(), hash_list(&[1, 2]));
+
+        rollback_feed(&feed, &directory).unwrap();
+        assert_eq!(fs::read(feed.local_copy(&directory)).unwrap(), hash_list(&[1, 2]));
+        let signatures = load_signatures(std::slice::from_ref(&feed), &directory);
+        assert!(is_flagged(&signatures, 1));
+        assert!(!is_flagged(&signatures, 3));
+        // Second rollback undoes the first one
+        rollback_feed(&feed, &directory).unwrap();
+        assert_eq!(fs::read(feed.local_copy(&directory)).unwrap(), hash_list(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn rolled_back_copy_is_downloaded_whole() {
+        let server = FeedServer::start();
+        let directory = test_directory("rolled-back");
+        let feed = test_feed(&server, true);
+        server.serve_verified("/full.txt", &hash_list(&[1]));
+        server.serve_verified("/recent.txt", &hash_list(&[5]));
+        update_feed(&feed, &directory).unwrap();
+        update_feed(&feed, &directory).unwrap();
+        rollback_feed(&feed, &directory).unwrap();
+
+        // Bad delta is still published, it must not be merged into the rolled back copy again
+        assert_eq!(update_feed(&feed, &directory).unwrap(), FeedUpdate::Full { hashes: 1 });
+        assert_eq!(server.requests_of("/recent.txt"), 1);
+        assert!(!is_flagged(&load_signatures(std::slice::from_ref(&feed), &directory), 5));
+        // Recent export is used again after the whole download
+        assert!(matches!(update_feed(&feed, &directory).unwrap(), FeedUpdate::Recent { .. }));
+    }
+
+    #[test]
+    fn copy_older_than_the_recent_export_is_downloaded_whole() {
+        let server = FeedServer::start();
+        let directory = test_directory("stale");
+        let feed = test_feed(&server, true);
+        server.serve_verified("/full.txt", &hash_list(&[1]));
+        server.serve_verified("/recent.txt", &hash_list(&[2]));
+        update_feed(&feed, &directory).unwrap();
+
+        let long_ago = unix_now() - 49 * 3600;
+        feed.save_state(&directory, FeedState { full_at: long_ago, updated_at: long_ago, rolled_back: false }).unwrap();
+        assert!(feed.is_due(&directory));
+        server.serve_verified("/full.txt", &hash_list(&[1, 4]));
+        assert_eq!(update_feed(&feed, &directory).unwrap(), FeedUpdate::Full { hashes: 2 });
+        assert_eq!(server.requests_of("/full.txt"), 2);
+        assert_eq!(server.requests_of("/recent.txt"), 0);
+    }
+
+    #[test]
+    fn legacy_hashes_are_imported_once() {
+        let server = FeedServer::start();
+        let directory = test_directory("legacy");
+        let mut feed = test_feed(&server, true);
+        feed.name = DEFAULT_FEED_NAME.to_string();
+        let legacy = std::env::temp_dir().join(format!("rustguardian-hashes-{}.txt", std::process::id()));
+        fs::write(&legacy, hash_list(&[1, 2])).unwrap();
+
+        assert_eq!(import_legacy_hashes(std::slice::from_ref(&feed), &directory, &legacy).unwrap(), 2);
+        assert!(!feed.is_due(&directory));
+        assert!(is_flagged(&load_signatures(std::slice::from_ref(&feed), &directory), 2));
+        // Copy of the feed is never replaced by the old file again
+        fs::write(&legacy, hash_list(&[3])).unwrap();
+        assert_eq!(import_legacy_hashes(std::slice::from_ref(&feed), &directory, &legacy).unwrap(), 0);
+        assert_eq!(fs::read(feed.local_copy(&directory)).unwrap(), hash_list(&[1, 2]));
+        fs::remove_file(&legacy).unwrap();
+    }
+
+    #[test]
+    fn unverified_recent_export_falls_back_to_the_whole_feed() {
+        let server = FeedServer::start();
+        let directory = test_directory("fallback");
+        let feed = test_feed(&server, true);
+        server.serve_verified("/full.txt", &hash_list(&[1]));
+        update_feed(&feed, &directory).unwrap();
+
+        server.serve_verified("/recent.txt", &hash_list(&[2]));
+        server.serve("/recent.txt", &hash_list(&[5]));
+        assert_eq!(update_feed(&feed, &directory).unwrap(), FeedUpdate::Full { hashes: 1 });
+        assert_eq!(server.requests_of("/recent.txt"), 1);
+        assert!(!is_flagged(&load_signatures(&[feed], &directory), 5));
+    }
+}
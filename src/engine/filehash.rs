@@ -1,53 +1,281 @@
-use std::{fs, path::Path};
+use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 use sha2::{Sha256, Digest};
-use std::collections::HashSet;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use sha1::Sha1;
+use md5::Md5;
+use std::fs::OpenOptions;
+use std::io::{self, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use crate::push_log;
+use super::scan_jobs::ScanProgress;
+use super::feeds::{FileDigests, HashAlgorithm, SignatureSet};
 
-// This function will load hashes of viruses so the program could
-// compare them with the hashes on flash drive
-pub fn load_hashes_from_file(path: &str) -> HashSet<String> {
-    let file = File::open(path).expect("Cannot open hash file");
-    let reader = BufReader::new(file);
+// Files are read in chunks of this size, so a huge file never has to fit into memory
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+// Paths waiting for a worker per worker, the walk stops when the workers fall behind
+const QUEUED_PATHS_PER_WORKER: usize = 4;
+// Unreadable entries logged one by one, the rest are only counted
+const MAX_LOGGED_ERRORS: usize = 10;
 
-    reader
-        .lines()
-        .filter_map(|line| line.ok())
-        .map(|line| line.trim().to_lowercase()) 
-        .collect()
+/// File listed by at least one feed
+#[derive(Clone, Debug)]
+pub struct FileMatch {
+    pub path: String,
+    /// Names of the feeds that flagged the file
+    pub feeds: Vec<String>,
 }
 
-/// Computes the SHA-256 hash of a single file at the given path.
-/// Returns the hexadecimal string representation of the hash.
-fn hash_file(path: &Path) -> Option<String> {
-    let data = fs::read(path).ok()?; // Read file contents
-    let mut hasher = Sha256::new(); // Create SHA-256 hasher
-    hasher.update(&data); // Feed file data into the hasher
-    let result = hasher.finalize(); // Finalize and get the hash
-    Some(format!("{:x}", result)) // Return hash as hex string
+/// Result of hashing all files of a directory
+#[derive(Clone, Debug, Default)]
+pub struct ScanSummary {
+    pub files: usize,
+    pub bytes: u64,
+    /// Files whose hash is in the virus hashes
+    pub matches: Vec<FileMatch>,
+    /// Entries that could not be read or hashed
+    pub errors: usize,
+    /// Symlinks and special files
+    pub skipped: usize,
+    pub elapsed: Duration,
+    pub cancelled: bool,
 }
 
+impl ScanSummary {
+    pub fn describe(&self) -> String {
+        format!(
+            "{} files ({:.1} MB) in {:.1} s, {} matches, {} errors, {} skipped",
+            self.files,
+            self.bytes as f64 / (1024.0 * 1024.0),
+            self.elapsed.as_secs_f64(),
+            self.matches.len(),
+            self.errors,
+            self.skipped
+        )
+    }
+}
+
+/// Counts the regular files under the directory and their size, so the progress of the scan is known
+pub fn count_files(dir: &Path, progress: &ScanProgress) -> (u64, u64) {
+    let mut files = 0u64;
+    let mut bytes = 0u64;
+    for entry in WalkDir::new(dir).into_iter().filter_map(|entry| entry.ok()) {
+        if progress.is_cancelled() {
+            break;
+        }
+        if entry.file_type().is_file() {
+            files += 1;
+            bytes += entry.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        }
+    }
+    (files, bytes)
+}
+
+// Hashers of the algorithms the loaded feeds need, so a file is read only once for all of them
+#[derive(Default)]
+struct Hashers {
+    md5: Option<Md5>,
+    sha1: Option<Sha1>,
+    sha256: Option<Sha256>,
+}
+
+impl Hashers {
+    fn new(algorithms: &[HashAlgorithm]) -> Self {
+        let mut hashers = Hashers::default();
+        for algorithm in algorithms {
+            match algorithm {
+                HashAlgorithm::Md5 => hashers.md5 = Some(Md5::new()),
+                HashAlgorithm::Sha1 => hashers.sha1 = Some(Sha1::new()),
+                HashAlgorithm::Sha256 => hashers.sha256 = Some(Sha256::new()),
+            }
+        }
+        hashers
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        if let Some(hasher) = &mut self.md5 {
+            hasher.update(data);
+        }
+        if let Some(hasher) = &mut self.sha1 {
+            hasher.update(data);
+        }
+        if let Some(hasher) = &mut self.sha256 {
+            hasher.update(data);
+        }
+    }
 
+    fn finalize(self) -> FileDigests {
+        let mut digests = Vec::new();
+        if let Some(hasher) = self.md5 {
+            digests.push((HashAlgorithm::Md5, hasher.finalize().to_vec()));
+        }
+        if let Some(hasher) = self.sha1 {
+            digests.push((HashAlgorithm::Sha1, hasher.finalize().to_vec()));
+        }
+        if let Some(hasher) = self.sha256 {
+            digests.push((HashAlgorithm::Sha256, hasher.finalize().to_vec()));
+        }
+        FileDigests(digests)
+    }
+}
+
+/// Computes the hashes of a single file at the given path with the given algorithms, reading it in chunks.
+/// Returns the raw digests and the number of bytes read.
+/// Symlinks and special files (FIFOs, devices, sockets) are refused, opening a FIFO would block forever
+fn hash_file(path: &Path, buffer: &mut [u8], algorithms: &[HashAlgorithm], progress: &ScanProgress) -> io::Result<(FileDigests, u64)> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)?;
+    // File could have been replaced after the walk saw it
+    if !file.metadata()?.is_file() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a regular file"));
+    }
+    let mut hashers = Hashers::new(algorithms);
+    let mut bytes = 0u64;
+    loop {
+        // Cancelled scan does not wait for the end of a huge file
+        if progress.is_cancelled() {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "scan was cancelled"));
+        }
+        let read = match file.read(buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hashers.update(&buffer[..read]); // Feed file data into the hashers
+        bytes += read as u64;
+        progress.bytes_done.fetch_add(read as u64, Ordering::Relaxed);
+    }
+    Ok((hashers.finalize(), bytes))
+}
+
+// Result of one file from a worker
+enum HashResult {
+    Hashed { path: PathBuf, digests: FileDigests, bytes: u64 },
+    Failed { path: PathBuf, error: io::Error },
+}
+
+fn log_unreadable(errors: &mut usize, message: String) {
+    *errors += 1;
+    if *errors <= MAX_LOGGED_ERRORS {
+        push_log(format!("[WARNING] {}", message));
+    } else if *errors == MAX_LOGGED_ERRORS + 1 {
+        push_log("[WARNING] More entries could not be read, only their count is logged".to_string());
+    }
+}
 
 /// Recursively walks through all files in the given directory,
-/// computes SHA-256 hash for each file, and returns a vector of (file_path, hash) pairs.
-pub fn hash_all_files_in_dir(dir: &Path, hash_set: &HashSet<String>) -> Vec<(String, String)> {
-    let mut hashes = Vec::new();
-    for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
-        let path = entry.path();
-        if path.is_file() {
-            if let Some(hash) = hash_file(path) {
-                let hash_clone = hash.clone(); 
-                //push_log(format!("File with path {} has hash {} ", path.display(), hash));
-                hashes.push((path.display().to_string(), hash_clone));
-                if hash_set.contains(&hash) {
-                    push_log(format!("[ALERT] File with path: {} is malicious", path.display().to_string() ))
+/// computes the hashes the feeds need for each file on a pool of workers (one per CPU),
+/// and looks them up in the indexes of the feeds. Progress is counted in the progress of the scan job.
+/// Symlinks are not followed, so a link loop or a link out of the drive is never read
+pub fn hash_all_files_in_dir(dir: &Path, signatures: &SignatureSet, progress: &Arc<ScanProgress>) -> ScanSummary {
+    let started = Instant::now();
+    let algorithms = Arc::new(signatures.algorithms());
+    let workers = thread::available_parallelism().map(|count| count.get()).unwrap_or(1);
+    let (path_sender, path_receiver) = mpsc::sync_channel::<PathBuf>(workers * QUEUED_PATHS_PER_WORKER);
+    let path_receiver = Arc::new(Mutex::new(path_receiver));
+    let (result_sender, result_receiver) = mpsc::channel::<HashResult>();
+    let mut handles = Vec::with_capacity(workers);
+    for _ in 0..workers {
+        let path_receiver = path_receiver.clone();
+        let result_sender = result_sender.clone();
+        let progress = progress.clone();
+        let algorithms = algorithms.clone();
+        handles.push(thread::spawn(move || {
+            let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+            loop {
+                // Lock is held only while taking the next path
+                let path = match path_receiver.lock().unwrap().recv() {
+                    Ok(path) => path,
+                    Err(_) => break,
+                };
+                let result = match hash_file(&path, &mut buffer, &algorithms, &progress) {
+                    Ok((digests, bytes)) => HashResult::Hashed { path, digests, bytes },
+                    Err(error) => HashResult::Failed { path, error },
+                };
+                if result_sender.send(result).is_err() {
+                    break;
                 }
             }
+        }));
+    }
+    drop(result_sender);
+
+    let mut summary = ScanSummary::default();
+    let handle_result = |result: HashResult, summary: &mut ScanSummary| {
+        progress.files_done.fetch_add(1, Ordering::Relaxed);
+        match result {
+            HashResult::Hashed { path, digests, bytes } => {
+                summary.files += 1;
+                summary.bytes += bytes;
+                let feeds = signatures.matching_feeds(&digests);
+                if !feeds.is_empty() {
+                    push_log(format!("[ALERT] File with path: {} is malicious, flagged by {}", path.display(), feeds.join(", ")));
+                    summary.matches.push(FileMatch { path: path.display().to_string(), feeds });
+                }
+            }
+            // Files stopped by the cancel are not errors
+            HashResult::Failed { .. } if progress.is_cancelled() => {}
+            HashResult::Failed { path, error } => {
+                log_unreadable(&mut summary.errors, format!("File {} could not be hashed: {}", path.display(), error))
+            }
+        }
+    };
+    for entry in WalkDir::new(dir) {
+        if progress.is_cancelled() {
+            break;
+        }
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                log_unreadable(&mut summary.errors, format!("Entry could not be read: {}", e));
+                continue;
+            }
+        };
+        // Type of the entry itself, a symlink is not followed to its target
+        if !entry.file_type().is_file() {
+            if !entry.file_type().is_dir() {
+                summary.skipped += 1;
+            }
+            continue;
+        }
+        if path_sender.send(entry.into_path()).is_err() {
+            break;
+        }
+        // Results are taken while walking, so they do not pile up in memory
+        while let Ok(result) = result_receiver.try_recv() {
+            handle_result(result, &mut summary);
+        }
+    }
+    drop(path_sender);
+    for result in result_receiver {
+        handle_result(result, &mut summary);
+    }
+    for handle in handles {
+        if let Err(e) = handle.join() {
+            push_log(format!("[ERROR] Hash worker failed: {:?}", e));
         }
     }
 
-    hashes
+    summary.elapsed = started.elapsed();
+    summary.cancelled = progress.is_cancelled();
+    let seconds = summary.elapsed.as_secs_f64();
+    let megabytes = summary.bytes as f64 / (1024.0 * 1024.0);
+    push_log(format!(
+        "[INFO] Hashed {} files ({:.1} MB) of {} in {:.1} s, {:.1} MB/s on {} workers, {} symlinks and special files skipped, {} unreadable",
+        summary.files,
+        megabytes,
+        dir.display(),
+        seconds,
+        if seconds > 0.0 { megabytes / seconds } else { 0.0 },
+        workers,
+        summary.skipped,
+        summary.errors
+    ));
+    summary
 }
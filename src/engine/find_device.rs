@@ -1,8 +1,14 @@
-use udev::{MonitorBuilder, EventType};
-use super::{keylogger, whitelist::WHITELIST};
-use std::thread;
-use std::time::Duration;
-use std::sync::Arc;
+use udev::{MonitorBuilder, EventType, Enumerator};
+use super::keylogger::{self, MonitoredDevice};
+use super::pointer;
+use super::config::config;
+use super::whitelist::{is_whitelisted, DeviceIdentity};
+use super::input_hold::{impersonates_replay_device, is_replay_device};
+use super::transport::InputTransport;
+use std::collections::HashSet;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use std::sync::{Arc, Mutex};
 use crate::push_log;
 use std::sync::atomic::{AtomicBool, Ordering};
 
@@ -14,63 +20,35 @@ pub fn find_all_devices(running: Arc<AtomicBool>) -> std::io::Result<()> {
     // Variables for controling the child threads
     let keyloggers_running = Arc::new(AtomicBool::new(true));
     let mut keylogger_threads = Vec::new();
-    //let mut seen_devnodes = std::collections::HashSet::new();
+    // Devnodes that are monitored right now, so the same keyboard never gets two keyloggers
+    let seen_devnodes: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+
+    // Keyboards and pointers that were plugged in before LockDown was enabled are checked the same way as new ones.
+    // The monitor is already listening, so a device plugged in right now is not missed
+    let mut enumerator = Enumerator::new()?;
+    enumerator.match_subsystem("input")?;
+    // Property matches are joined with OR
+    enumerator.match_property("ID_INPUT_KEYBOARD", "1")?;
+    enumerator.match_property("ID_INPUT_MOUSE", "1")?;
+    enumerator.match_property("ID_INPUT_TABLET", "1")?;
+    let mut attached_devices = 0;
+    for device in enumerator.scan_devices()? {
+        // Plug-in time of these devices says nothing, they were attached before LockDown
+        if let Some(handle) = start_input_monitoring(&device, None, &seen_devnodes, &keyloggers_running) {
+            keylogger_threads.push(handle);
+            attached_devices += 1;
+        }
+    }
+    push_log(format!("[INFO] {} already attached input devices are not whitelisted and will be analysed", attached_devices));
+
     // Run while flag running is true (could be stopped from main.rs)
     while running.load(Ordering::Relaxed) {
         // Iterate through all events
         if let Some(event) = monitor.iter().next() {
             if event.event_type() == EventType::Add {
-                let device = event.device();
-                // Check if the device is a keyboard 
-                if let Some(id_input_keyboard) = device.property_value("ID_INPUT_KEYBOARD") {
-                    // Check if we found the keyboard
-                    if id_input_keyboard.to_str() == Some("1") {
-                        let mut name_str: String = String::from("UNKNOWN");
-                        // Retrieving the device characteristics
-                        if let Some(name) = device.property_value("NAME") {
-                            name_str = name.to_string_lossy().into_owned();
-                        }
-                        name_str = name_str.trim().trim_matches('"').to_string();
-                        for k in WHITELIST.read().unwrap().keys() {
-                            push_log(format!("WHITELIST KEY: {:?}", k));
-                        }
-                        // Check if device is whitelisted
-                        if WHITELIST.read().unwrap().contains_key(&name_str){
-                            continue
-                        }
-                        let mut dev_identificator: String = String::from("NULL");
-                        match device.parent_with_subsystem("usb") {
-                            Ok(Some(parent)) => {
-                                // We have a parent device with subsystem "usb"
-                                let parent_path = parent.sysname();
-                                dev_identificator = parent_path.to_string_lossy().into_owned();
-                            }
-                            Ok(None) => {
-                                // Subsystem "usb" not found in the parents
-                                push_log(format!("Subsystem USB not found in the parents"));
-                            }
-                            Err(e) => {
-                                // An error occurred reading the parent device
-                                push_log(format!("Error looking up USB parent: {}", e));
-                            }
-                        }
-
-                        if let Some(devnode) = device.devnode() {
-                            // Creating a datapath that will contain events for the new keyboard
-                            let devnode_str = devnode.to_str().unwrap().to_string();
-                            
-                            let keyloggers_running_clone = keyloggers_running.clone();
-                            // Start logging in a new thread
-                            let handle = thread::spawn(move || {
-                                push_log("[INFO] Starting logging new events on device".to_string());
-                                if let Err(e) = keylogger::start_logging(&devnode_str, &dev_identificator, &name_str, keyloggers_running_clone) {
-                                    eprintln!("Error in keylogger: {}", e);
-                                }
-                            });
-                            keylogger_threads.push(handle);
-
-                        }
-                    }
+                let plugged_in = plug_in_time(&event.device());
+                if let Some(handle) = start_input_monitoring(&event.device(), Some(plugged_in), &seen_devnodes, &keyloggers_running) {
+                    keylogger_threads.push(handle);
                 }
             }
         }
@@ -83,7 +61,150 @@ pub fn find_all_devices(running: Arc<AtomicBool>) -> std::io::Result<()> {
             push_log(format!("Failed to join keylogger thread: {:?}", e));
         }
     }
-    
+
     push_log("Device monitoring stopped".to_string());
     Ok(())
-}
\ No newline at end of file
+}
+
+/* Time udev finished adding the device. USEC_INITIALIZED is on the monotonic clock,
+ * it is moved to the wall clock that the key events use. Falls back to now when it is missing */
+fn plug_in_time(device: &udev::Device) -> SystemTime {
+    let now = SystemTime::now();
+    let initialized_us = match device
+        .property_value("USEC_INITIALIZED")
+        .and_then(|value| value.to_str())
+        .and_then(|value| value.parse::<u64>().ok())
+    {
+        Some(initialized_us) => initialized_us,
+        None => return now,
+    };
+    let mut monotonic = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut monotonic) } != 0 {
+        return now;
+    }
+    let monotonic_us = monotonic.tv_sec as u64 * 1_000_000 + monotonic.tv_nsec as u64 / 1000;
+    now.checked_sub(Duration::from_micros(monotonic_us.saturating_sub(initialized_us))).unwrap_or(now)
+}
+
+// Input devices that LockDown analyses
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum InputKind {
+    Keyboard,
+    /// Mouse or tablet
+    Pointer,
+}
+
+impl InputKind {
+    fn of(device: &udev::Device) -> Option<Self> {
+        let has_property = |name: &str| device.property_value(name).and_then(|value| value.to_str()) == Some("1");
+        // Keyboard and mouse on the same node are analysed as a keyboard, keystrokes are the bigger threat
+        if has_property("ID_INPUT_KEYBOARD") {
+            Some(InputKind::Keyboard)
+        } else if (has_property("ID_INPUT_MOUSE") || has_property("ID_INPUT_TABLET")) && config().pointer_detector.enabled {
+            Some(InputKind::Pointer)
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            InputKind::Keyboard => "keyboard",
+            InputKind::Pointer => "pointer device",
+        }
+    }
+}
+
+/* Starts the keylogger or pointer analyser thread if the device is a keyboard, mouse or tablet
+ * that is not whitelisted and is not monitored yet. Returns handle of the started thread */
+fn start_input_monitoring(
+    device: &udev::Device,
+    plugged_in: Option<SystemTime>,
+    seen_devnodes: &Arc<Mutex<HashSet<String>>>,
+    keyloggers_running: &Arc<AtomicBool>,
+) -> Option<JoinHandle<()>> {
+    // Check if the device is a keyboard or a pointer
+    let kind = InputKind::of(device)?;
+    // Only event nodes can be read, the parent input device has no devnode and mouseN nodes are not evdev
+    if !device.sysname().to_string_lossy().starts_with("event") {
+        return None;
+    }
+    // Virtual keyboards that replay held back input are created by RustGuardian itself.
+    // They are recognized by the syspath uinput gave them, never by the name that any process can choose
+    if is_replay_device(device.syspath()) {
+        return None;
+    }
+    let devnode_str = device.devnode()?.to_str()?.to_string();
+    if seen_devnodes.lock().unwrap().contains(&devnode_str) {
+        return None;
+    }
+
+    let mut name_str: String = String::from("UNKNOWN");
+    // Retrieving the device characteristics, event nodes usually have the name only on their parent
+    if let Some(name) = device.property_value("NAME") {
+        name_str = name.to_string_lossy().into_owned();
+    } else if let Some(parent) = device.parent() {
+        if let Some(name) = parent.property_value("NAME") {
+            name_str = name.to_string_lossy().into_owned();
+        }
+    }
+    name_str = name_str.trim().trim_matches('"').to_string();
+    // Same name as our replay keyboards, the device is classified and its uinput owners are looked up as usual
+    if impersonates_replay_device(device.syspath(), &name_str) {
+        push_log(format!("[ALERT] Input device {} uses the name of RustGuardian replay keyboards, it is analysed", name_str));
+    }
+    let transport = InputTransport::from_udev(device);
+    // Built-in keyboard, touchpad and buttons can not be whitelisted, they are trusted unless configured otherwise
+    if plugged_in.is_none() && transport.is_built_in() && !config().built_in_devices.monitor {
+        push_log(format!("[INFO] Built-in {} {} ({}) is not analysed", kind.name(), name_str, transport.describe()));
+        return None;
+    }
+    let mut identity = None;
+    match &transport {
+        InputTransport::Usb { port_path } => {
+            // We have a parent device with subsystem "usb"
+            identity = udev::Device::from_subsystem_sysname("usb".to_string(), port_path.clone())
+                .ok()
+                .and_then(|parent| DeviceIdentity::from_udev(&parent));
+            if let Some(identity) = &identity {
+                // Check if device is whitelisted
+                if is_whitelisted(identity) {
+                    return None;
+                }
+                push_log(format!("[INFO] New usb {} {}", kind.name(), identity.describe()));
+            }
+        }
+        InputTransport::Virtual { .. } => {
+            // Input injected by a process through uinput never passes a USB port
+            push_log(format!("[WARNING] New virtual {} {} ({})", kind.name(), name_str, transport.describe()));
+        }
+        _ => {
+            push_log(format!("[INFO] New {} {} {} ({})", transport, kind.name(), name_str, transport.describe()));
+        }
+    }
+
+    // Remember the devnode until the keylogger is finished
+    seen_devnodes.lock().unwrap().insert(devnode_str.clone());
+    let seen_devnodes = seen_devnodes.clone();
+    let keyloggers_running_clone = keyloggers_running.clone();
+    let monitored = MonitoredDevice {
+        devnode: devnode_str.clone(),
+        syspath: device.syspath().to_string_lossy().into_owned(),
+        name: name_str,
+        identity,
+        transport,
+        plugged_in,
+    };
+    // Start logging in a new thread
+    Some(thread::spawn(move || {
+        push_log("[INFO] Starting logging new events on device".to_string());
+        let result = match kind {
+            InputKind::Keyboard => keylogger::start_logging(&monitored, keyloggers_running_clone),
+            InputKind::Pointer => pointer::start_pointer_monitoring(&monitored, keyloggers_running_clone),
+        };
+        if let Err(e) = result {
+            eprintln!("Error in {} monitoring: {}", kind.name(), e);
+        }
+        seen_devnodes.lock().unwrap().remove(&devnode_str);
+    }))
+}
@@ -0,0 +1,202 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::slice;
+use std::cmp::Ordering;
+use crate::push_log;
+use super::storage::invalid_data;
+
+// Start of every index file, the last two bytes are the format version
+const MAGIC: &[u8; 8] = b"RGHIDX01";
+const HEADER_LENGTH: usize = 64;
+
+/// Parses a digest of the given length in bytes written in hex, None for anything else
+pub fn parse_hex_digest(text: &str, digest_length: usize) -> Option<Vec<u8>> {
+    let text = text.trim();
+    if text.len() != digest_length * 2 || !text.is_ascii() {
+        return None;
+    }
+    let mut digest = vec![0u8; digest_length];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+// Size and modification time of the feed the index was built from, a different one means a new feed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct SourceStamp {
+    size: u64,
+    mtime_secs: i64,
+    mtime_nanos: u32,
+}
+
+impl SourceStamp {
+    fn of(source: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(source)?;
+        Ok(SourceStamp { size: metadata.size(), mtime_secs: metadata.mtime(), mtime_nanos: metadata.mtime_nsec() as u32 })
+    }
+}
+
+// Header of the index, all numbers are little endian:
+// magic (8), digest length (4), reserved (4), count (8), source size (8), source mtime secs (8), nanos (4), zeros
+fn render_header(digest_length: usize, count: u64, stamp: SourceStamp) -> [u8; HEADER_LENGTH] {
+    let mut header = [0u8; HEADER_LENGTH];
+    header[..8].copy_from_slice(MAGIC);
+    header[8..12].copy_from_slice(&(digest_length as u32).to_le_bytes());
+    header[16..24].copy_from_slice(&count.to_le_bytes());
+    header[24..32].copy_from_slice(&stamp.size.to_le_bytes());
+    header[32..40].copy_from_slice(&stamp.mtime_secs.to_le_bytes());
+    header[40..44].copy_from_slice(&stamp.mtime_nanos.to_le_bytes());
+    header
+}
+
+fn parse_header(header: &[u8], digest_length: usize) -> io::Result<(u64, SourceStamp)> {
+    if header.len() < HEADER_LENGTH || &header[..8] != MAGIC {
+        return Err(invalid_data("not a RustGuardian hash index"));
+    }
+    let number = |range: std::ops::Range<usize>| {
+        let mut bytes = [0u8; 8];
+        bytes[..range.len()].copy_from_slice(&header[range]);
+        u64::from_le_bytes(bytes)
+    };
+    if number(8..12) != digest_length as u64 {
+        return Err(invalid_data("hash index has digests of another length"));
+    }
+    let stamp = SourceStamp {
+        size: number(24..32),
+        mtime_secs: number(32..40) as i64,
+        mtime_nanos: number(40..44) as u32,
+    };
+    Ok((number(16..24), stamp))
+}
+
+/// Compiles the feed (one hex digest per line) into a sorted index of raw digests.
+/// Lines that are not a digest of this length are skipped. Returns the number of digests
+pub fn build_index(source: &Path, index: &Path, digest_length: usize) -> io::Result<usize> {
+    let stamp = SourceStamp::of(source)?;
+    let mut digests: Vec<Vec<u8>> = BufReader::new(File::open(source)?)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| parse_hex_digest(&line, digest_length))
+        .collect();
+    digests.sort_unstable();
+    digests.dedup();
+    // Written next to the index and renamed over it, a running scan keeps its mapping of the old file
+    let mut tmp_name = index.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = Path::new(&tmp_name);
+    {
+        let mut writer = BufWriter::new(File::create(tmp_path)?);
+        writer.write_all(&render_header(digest_length, digests.len() as u64, stamp))?;
+        for digest in &digests {
+            writer.write_all(digest)?;
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    }
+    fs::rename(tmp_path, index)?;
+    Ok(digests.len())
+}
+
+/// Sorted malware digests mapped from the index file, looked up by binary search.
+/// Only the pages that are touched are read, so loading takes no time and little memory
+pub struct HashIndex {
+    map: *mut libc::c_void,
+    map_length: usize,
+    digest_length: usize,
+    count: usize,
+    /// Digests added in memory, not part of the index file
+    extra: Vec<Vec<u8>>,
+}
+
+// Mapping is read only and lives as long as the index
+unsafe impl Send for HashIndex {}
+unsafe impl Sync for HashIndex {}
+
+impl HashIndex {
+    /// Maps the index file, its size has to match the count in its header
+    pub fn open(index: &Path, digest_length: usize) -> io::Result<Self> {
+        let mut file = File::open(index)?;
+        let mut header = [0u8; HEADER_LENGTH];
+        file.read_exact(&mut header)?;
+        let (count, _) = parse_header(&header, digest_length)?;
+        let map_length = file.metadata()?.len() as usize;
+        if map_length != HEADER_LENGTH + count as usize * digest_length {
+            return Err(invalid_data("hash index is truncated"));
+        }
+        let map = unsafe { libc::mmap(std::ptr::null_mut(), map_length, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0) };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(HashIndex { map, map_length, digest_length, count: count as usize, extra: Vec::new() })
+    }
+
+    /// Opens the index of the feed, it is built again only when the feed changed since the last build
+    pub fn load_or_build(source: &Path, index: &Path, digest_length: usize) -> io::Result<Self> {
+        let stamp = SourceStamp::of(source)?;
+        let up_to_date = File::open(index)
+            .and_then(|mut file| {
+                let mut header = [0u8; HEADER_LENGTH];
+                file.read_exact(&mut header)?;
+                parse_header(&header, digest_length)
+            })
+            .is_ok_and(|(_, built_from)| built_from == stamp);
+        if !up_to_date {
+            push_log(format!("[INFO] Building the hash index {} from {}", index.display(), source.display()));
+            let count = build_index(source, index, digest_length)?;
+            push_log(format!("[INFO] Hash index was built with {} hashes", count));
+        }
+        Self::open(index, digest_length)
+    }
+
+    /// Adds digests that are checked together with the index
+    pub fn with_extra(mut self, hex_digests: &[&str]) -> Self {
+        self.extra.extend(hex_digests.iter().filter_map(|digest| parse_hex_digest(digest, self.digest_length)));
+        self
+    }
+
+    fn digests(&self) -> &[u8] {
+        // Digests follow the header one after another
+        unsafe { slice::from_raw_parts((self.map as *const u8).add(HEADER_LENGTH), self.count * self.digest_length) }
+    }
+
+    pub fn digest_length(&self) -> usize {
+        self.digest_length
+    }
+
+    pub fn contains(&self, digest: &[u8]) -> bool {
+        if digest.len() != self.digest_length {
+            return false;
+        }
+        let digests = self.digests();
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let start = middle * self.digest_length;
+            match digests[start..start + self.digest_length].cmp(digest) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return true,
+            }
+        }
+        self.extra.iter().any(|extra| extra == digest)
+    }
+
+    pub fn len(&self) -> usize {
+        self.count + self.extra.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for HashIndex {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map, self.map_length);
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use evdev::{AttributeSet, Device, InputEvent, Key};
+use lazy_static::lazy_static;
+use crate::push_log;
+
+/// Name prefix of the virtual keyboards that replay held back input. Any process can pick this name,
+/// so it is only shown to the user and never used to recognize our own devices
+pub const REPLAY_DEVICE_PREFIX: &str = "RustGuardian replay";
+// uinput rejects longer device names
+const MAX_DEVICE_NAME_LENGTH: usize = 79;
+
+lazy_static! {
+    // Syspaths (/sys/devices/virtual/input/inputN) of the replay devices that exist right now
+    static ref REPLAY_DEVICES: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+/// True when the event node belongs to a replay device created by this process
+pub fn is_replay_device(syspath: &Path) -> bool {
+    let devices = REPLAY_DEVICES.lock().unwrap();
+    // Event nodes are children of the input device that uinput reports
+    devices.contains(syspath) || syspath.parent().is_some_and(|parent| devices.contains(parent))
+}
+
+/// Device has the name of a replay keyboard but was not created by this process
+pub fn impersonates_replay_device(syspath: &Path, name: &str) -> bool {
+    name.starts_with(REPLAY_DEVICE_PREFIX) && !is_replay_device(syspath)
+}
+
+/// Exclusively grabbed keyboard whose input does not reach the system until it is judged.
+/// Events are buffered while the device is held, once it is judged benign they are replayed
+/// through a virtual keyboard and every following event is forwarded the same way
+pub struct InputHold {
+    replay_device: VirtualDevice,
+    replay_syspath: PathBuf,
+    buffer: Vec<InputEvent>,
+    max_buffered_events: usize,
+    dropped_events: usize,
+    forwarding: bool,
+}
+
+impl InputHold {
+    /// Creates the replay device with the keys of the keyboard and grabs the keyboard
+    pub fn grab(device: &mut Device, device_name: &str, max_buffered_events: usize) -> io::Result<Self> {
+        let mut keys = AttributeSet::<Key>::new();
+        if let Some(supported_keys) = device.supported_keys() {
+            for key in supported_keys.iter() {
+                keys.insert(key);
+            }
+        }
+        let mut name = format!("{} {}", REPLAY_DEVICE_PREFIX, device_name);
+        if name.len() > MAX_DEVICE_NAME_LENGTH {
+            let cut = (0..=MAX_DEVICE_NAME_LENGTH).rev().find(|i| name.is_char_boundary(*i)).unwrap_or(0);
+            name.truncate(cut);
+        }
+        let mut replay_device = VirtualDeviceBuilder::new()?.name(&name).with_keys(&keys)?.build()?;
+        // Without its syspath the replay device could not be told apart from an injected keyboard
+        let replay_syspath = replay_device.get_syspath()?;
+        REPLAY_DEVICES.lock().unwrap().insert(replay_syspath.clone());
+        let hold = InputHold {
+            replay_device,
+            replay_syspath,
+            buffer: Vec::new(),
+            max_buffered_events,
+            dropped_events: 0,
+            forwarding: false,
+        };
+        // Dropping the hold on an error forgets the replay device again
+        device.grab()?;
+        Ok(hold)
+    }
+
+    /// True while the input is held back
+    pub fn is_holding(&self) -> bool {
+        !self.forwarding
+    }
+
+    /// Buffers the events while the device is held, otherwise forwards them to the system
+    pub fn push(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        if self.forwarding {
+            return self.replay_device.emit(events);
+        }
+        let free = self.max_buffered_events.saturating_sub(self.buffer.len());
+        if events.len() > free {
+            if self.dropped_events == 0 {
+                push_log(format!("[WARNING] Held back input is over {} events, newer events are dropped", self.max_buffered_events));
+            }
+            self.dropped_events += events.len() - free;
+        }
+        self.buffer.extend_from_slice(&events[..events.len().min(free)]);
+        Ok(())
+    }
+
+    /// Replays the buffered events and forwards all next events, returns the number of replayed events
+    pub fn release(&mut self) -> io::Result<usize> {
+        let buffered = std::mem::take(&mut self.buffer);
+        self.forwarding = true;
+        if !buffered.is_empty() {
+            self.replay_device.emit(&buffered)?;
+        }
+        Ok(buffered.len())
+    }
+
+    /// Throws the buffered events away, returns how many events never reached the system
+    pub fn discard(&mut self) -> usize {
+        let discarded = self.buffer.len() + self.dropped_events;
+        self.buffer.clear();
+        self.dropped_events = 0;
+        discarded
+    }
+}
+
+impl Drop for InputHold {
+    fn drop(&mut self) {
+        REPLAY_DEVICES.lock().unwrap().remove(&self.replay_syspath);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_device_is_recognized_by_syspath_only() {
+        let ours = PathBuf::from("/sys/devices/virtual/input/input9001");
+        REPLAY_DEVICES.lock().unwrap().insert(ours.clone());
+        assert!(is_replay_device(&ours.join("event31")));
+        assert!(!impersonates_replay_device(&ours.join("event31"), "RustGuardian replay USB Keyboard"));
+
+        // Another uinput device with the same name is still analysed and its owner looked up
+        let spoofed = Path::new("/sys/devices/virtual/input/input9002/event32");
+        assert!(!is_replay_device(spoofed));
+        assert!(impersonates_replay_device(spoofed, "RustGuardian replay USB Keyboard"));
+        assert!(!impersonates_replay_device(spoofed, "USB Keyboard"));
+        REPLAY_DEVICES.lock().unwrap().remove(&ours);
+    }
+}
@@ -1,154 +1,544 @@
-use evdev::{Device, InputEventKind, Key};
-use std::collections::HashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::fs::OpenOptions;
-use std::os::unix::fs::OpenOptionsExt;
-use std::io::{Write};
-use chrono::Local;
+use evdev::{Device, InputEvent, InputEventKind};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::sync::Arc;
 use crate::push_log;
 use std::sync::atomic::{AtomicBool, Ordering};
+use nix::errno::Errno;
 use nix::poll::{poll, PollFd, PollFlags};
 use std::os::unix::io::AsRawFd;
 use std::os::fd::BorrowedFd;
 use notify_rust::Notification;
+use super::quarantine::block_device;
+use super::config::{config, resolve_path};
+use super::keystroke_analysis::{KeystrokeDetector, KeystrokeVerdict};
+use super::command_rules::{RuleEngine, TextReconstructor};
+use super::keymap::KeyDecoder;
+use super::input_hold::InputHold;
+use super::challenge::{Challenge, ChallengeState};
+use super::whitelist::{DeviceIdentity, MatchPolicy};
+use super::transport::{DriverBinding, InputTransport};
+use super::virtual_input::{kill_process, UinputOwner};
+use super::session_log::{sessions_dir, SessionHeader, SessionLog};
+use super::user_session::ActivityProbe;
+use crate::whitelist_device_main;
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+// Built-in device judged malicious that waits for the user to disconnect it
+struct PendingDisconnect {
+    devnode: String,
+    name: String,
+    binding: DriverBinding,
+}
+
+lazy_static! {
+    static ref PENDING_DISCONNECTS: Mutex<Vec<PendingDisconnect>> = Mutex::new(Vec::new());
+}
+
+/// Keyboard or pointer device found by find_device that is monitored by the keylogger or the pointer analyser
+pub struct MonitoredDevice {
+    pub devnode: String,
+    pub syspath: String,
+    pub name: String,
+    pub identity: Option<DeviceIdentity>,
+    pub transport: InputTransport,
+    /// udev time of plugging the device in, None when it was attached before LockDown
+    pub plugged_in: Option<SystemTime>,
+}
+
+impl MonitoredDevice {
+    /// Header of the session file of this connection
+    pub fn session_header(&self) -> SessionHeader {
+        SessionHeader {
+            device: self.name.clone(),
+            identity: self.identity.as_ref().map(|identity| identity.describe()).unwrap_or_else(|| "-".to_string()),
+            transport: self.transport.describe(),
+            devnode: self.devnode.clone(),
+            syspath: self.syspath.clone(),
+            ..Default::default()
+        }
+    }
+}
 
 /* This function logs all events on the input device */
-pub fn start_logging(device_event_path: &str, device_path: &str, device_name: &str, running: Arc<AtomicBool>) -> std::io::Result<()> {
+pub fn start_logging(keyboard: &MonitoredDevice, running: Arc<AtomicBool>) -> std::io::Result<()> {
+    let device_name = keyboard.name.as_str();
+    let transport = &keyboard.transport;
     /* Open device events with the path that will be sent from find_device thread */
-    let mut device = Device::open(device_event_path).expect("Failed to open device");
+    let mut device = Device::open(&keyboard.devnode).expect("Failed to open device");
     let device_fd = device.as_raw_fd();
-    let mut log_file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .append(true)
-        .mode(0o600)
-        .open("logg.txt")?;
-    push_log("[INFO] All events from input device will be saved into logg.txt".to_string());
-    let now = Local::now(); // Gets local time
-    let timestamp = now.format("%Y-%m-%d %H:%M:%S").to_string();
-    write!(log_file, "[{}] Starting listening for events on the device with path: {}\n", timestamp, device_event_path)?;
-    log_file.flush()?;
+    // Every connection of every keyboard gets its own session file
+    let mut session = SessionLog::start(&sessions_dir(), keyboard.session_header());
+    if session.is_recorded() {
+        push_log(format!("[INFO] All events from keyboard {} will be saved into {}", device_name, session.path().display()));
+    }
     push_log(format!("Starting listening for keyboard activities"));
-    let key_map = create_keymap();
-    let mut backspace_found: bool = false;
-    let mut timestamps: Vec<u128> = Vec::new();
+    // Keys are decoded with the modifiers and the configured layout, so the log shows what the device really typed
+    let mut decoder = KeyDecoder::new(config().keyboard_layout);
+    session.event(&format!("keyboard layout {}", decoder.layout()));
+    let detector_config = config().keystroke_detector;
+    let report_interval = Duration::from_secs(detector_config.report_interval_secs);
+    let mut detector = KeystrokeDetector::new(detector_config);
+    if let Some(plugged_in) = keyboard.plugged_in {
+        let plugged_in_ms = plugged_in.duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis();
+        detector.set_plugged_in(plugged_in_ms);
+        session.event(&format!("plugged in at {}", chrono::DateTime::<chrono::Local>::from(plugged_in).format("%Y-%m-%d %H:%M:%S%.3f")));
+    }
+    // Keys typed into an idle or locked session are suspicious, nobody is sitting at the computer
+    let mut activity = ActivityProbe::new();
+    let mut inactive_typing_logged = false;
+    // The device is checked during its whole session, not only on the first keys
+    let mut first_verdict_logged = false;
+    let mut last_report = Instant::now();
+    let mut last_verdict: Option<KeystrokeVerdict> = None;
+    let mut max_risk_since_report: f64 = 0.0;
+    // Device that could not be blocked stays monitored, the user was already alerted about it
+    let mut block_failed = false;
+    // Typed text is checked against the rules file, it is read again for every new keyboard
+    let rules_config = config().command_rules;
+    let rules = if rules_config.enabled {
+        let rules_path = resolve_path(&rules_config.rules_file);
+        match RuleEngine::load(&rules_path) {
+            Ok(rules) => {
+                push_log(format!("[INFO] {} command rules loaded from {}", rules.rule_count(), rules_path.display()));
+                Some(rules)
+            }
+            Err(e) => {
+                push_log(format!("[ERROR] Failed to read command rules from {}: {}", rules_path.display(), e));
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut reconstructor = TextReconstructor::new();
+    // Input of the keyboard can be held back until the first verdict, so a payload does not run before it.
+    // The challenge needs the grab too, the code typed on the keyboard should not reach the system
+    let hold_config = config().hold_back;
+    let challenge_config = config().challenge;
+    let mut hold = if hold_config.enabled || challenge_config.enabled {
+        match InputHold::grab(&mut device, device_name, hold_config.max_buffered_events) {
+            Ok(hold) => {
+                push_log(format!("[INFO] Input of keyboard {} is held back until it is judged", device_name));
+                Some(hold)
+            }
+            Err(e) => {
+                push_log(format!("[ERROR] Failed to hold back input of keyboard {}: {}", device_name, e));
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut held_key_presses = 0;
+    let mut challenge = if challenge_config.enabled && hold.is_some() {
+        match Challenge::generate(challenge_config.code_length, challenge_config.attempts, Duration::from_secs(challenge_config.timeout_secs)) {
+            Ok(challenge) => {
+                announce_challenge(device_name, challenge.code());
+                Some(challenge)
+            }
+            Err(e) => {
+                push_log(format!("[ERROR] Failed to generate verification code: {}", e));
+                None
+            }
+        }
+    } else {
+        None
+    };
     let mut poll_fds = [PollFd::new(unsafe{BorrowedFd::borrow_raw(device_fd)}, PollFlags::POLLIN)];
 
     while running.load(Ordering::Relaxed) {
+        // Log the risk score over time, so it is visible how the device behaves
+        if last_report.elapsed() >= report_interval {
+            if let Some(verdict) = last_verdict.take() {
+                push_log(format!("[INFO] Keyboard {} {} (max risk {:.2} since last report)", device_name, verdict.describe(), max_risk_since_report));
+                session.event(&format!("verdict {}, max risk {:.2}", verdict.describe(), max_risk_since_report));
+                if !block_failed {
+                    session.set_verdict(&format!("benign, max risk {:.2}", max_risk_since_report), false);
+                }
+                max_risk_since_report = 0.0;
+            }
+            last_report = Instant::now();
+        }
+        // Keyboard that never types the code is not kept waiting forever
+        if challenge.as_ref().is_some_and(Challenge::is_expired) {
+            let reason = format!("verification code was not typed within {} seconds", challenge_config.timeout_secs);
+            discard_held_input(hold.take(), device_name);
+            challenge = None;
+            if !block_failed {
+                if block_input_device(&mut session, &mut device, keyboard, "keyboard", &reason, "challenge", &running) {
+                    return Ok(());
+                }
+                block_failed = true;
+            }
+        }
         match poll(&mut poll_fds, 100u16) {
             Ok(0) => continue, 
             Ok(_) => {
-                for ev in device.fetch_events().expect("Failed to fetch events") {
+                let events: Vec<InputEvent> = match device.fetch_events() {
+                    Ok(events) => events.collect(),
+                    Err(e) => {
+                        // Device was unplugged or blocked
+                        push_log(format!("[INFO] Keyboard {} is not available anymore ({}), monitoring stopped", device_name, e));
+                        break;
+                    }
+                };
+                let mut judged_benign = false;
+                let mut challenge_passed = false;
+                for ev in &events {
                     if let InputEventKind::Key(key) = ev.kind() {
-                        if ev.value() == 1 { //check time difference 
-                            let now = SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .expect("Time went backwards")
-                                .as_millis();
-                            /* Saving timestamp of click since we want to measure the difference in time between each clicks */
-                            timestamps.push(now);
-                            
-                            // Check the average speed of clicking for the first 7 clicks
-                            if timestamps.len() >= 7 {
-                                let mut time_diff_clicks: Vec<u128> = Vec::new();
-                                for i in 1..timestamps.len() {
-                                    time_diff_clicks.push(timestamps[i] - timestamps[i - 1]);
-                                }
-                                let alltime: u128 = time_diff_clicks.iter().sum();
-                                let avg_speed_in_ms = alltime as f32 / time_diff_clicks.len() as f32;
-                                let mut too_small_diff = 0;
-                                for &diff in &time_diff_clicks {
-                                    if (diff as f32 - avg_speed_in_ms).abs() < 40.0 {
-                                        too_small_diff += 1;
+                        /* Kernel timestamp of the event since we want to measure presses, releases and the time between them */
+                        let now = ev
+                            .timestamp()
+                            .duration_since(UNIX_EPOCH)
+                            .expect("Time went backwards")
+                            .as_millis();
+                        if ev.value() == 1 {
+                            let current = activity.current();
+                            if current.is_inactive() && !inactive_typing_logged {
+                                inactive_typing_logged = true;
+                                push_log(format!("[WARNING] Keyboard {} types while the user session is {}", device_name, current.describe()));
+                                session.event(&format!("typing while the user session is {}", current.describe()));
+                            }
+                            detector.set_user_activity(current);
+                        }
+                        let verdict = detector.key_event(key, ev.value(), now);
+                        if let Some(decoded) = decoder.key_event(key, ev.value()) {
+                            session.key(&decoded);
+                            if let Some(pending) = challenge.as_mut() {
+                                match pending.key(&decoded) {
+                                    ChallengeState::Typing => {}
+                                    ChallengeState::Passed => {
+                                        push_log(format!("[RESULT] Verification code was typed on keyboard {}, the keyboard is allowed", device_name));
+                                        session.event("verification code typed");
+                                        session.set_verdict("verified by code", false);
+                                        if challenge_config.auto_whitelist {
+                                            match transport {
+                                                InputTransport::Usb { port_path } => whitelist_keyboard(port_path, challenge_config.whitelist_policy),
+                                                _ => push_log(format!("[INFO] Keyboard {} is not a USB device and could not be added to whitelist", device_name)),
+                                            }
+                                        }
+                                        challenge = None;
+                                        challenge_passed = true;
+                                    }
+                                    ChallengeState::WrongCode { attempts_left } => {
+                                        push_log(format!("[WARNING] Wrong verification code typed on keyboard {}, {} attempts left", device_name, attempts_left));
+                                    }
+                                    ChallengeState::Failed => {
+                                        let reason = format!("wrong verification code typed {} times", challenge_config.attempts.max(1));
+                                        discard_held_input(hold.take(), device_name);
+                                        challenge = None;
+                                        if !block_failed {
+                                            if block_input_device(&mut session, &mut device, keyboard, "keyboard", &reason, "challenge", &running) {
+                                                return Ok(());
+                                            }
+                                            block_failed = true;
+                                        }
                                     }
                                 }
-                                if too_small_diff > 5 {
-                                    push_log(format!("[WARNING] RustGuardian registered BadUSB attack, the device will be unmounted"));
-                                    unmount_device(device_path)?;
-                                    // Send notification
-                                    Notification::new()
-                                        .summary("USB Device Alert")
-                                        .body(&format!("Registered BadUSB attack, the device {} will be disconnected \n", device_name))
-                                        .icon("dialog-warning")
-                                        .show()
-                                        .ok(); 
-                                    push_log(format!("[ACTION] Device was succesfully removed"));
-                                }
-                                else{
-                                    push_log("[RESULT] The device was scanned, not a BAD USB".to_string());
-                                }
-                                return Ok(());
                             }
-                            // If input device uses backspace we will go to a newline in logg.txt
-                            if key != Key::KEY_BACKSPACE {
-                                backspace_found = false;
-                                if let Some(character) = key_map.get(&key) {
-                                    log_file.write_all(character.as_bytes())?;
-                                    log_file.flush()?;
+                            if let Some(rules) = &rules {
+                                if reconstructor.push(&decoded) {
+                                    if let Some(rule) = rules.check(reconstructor.line()) {
+                                        let reason = format!("typed command matching rule {} ({})", rule.name, rule.source);
+                                        discard_held_input(hold.take(), device_name);
+                                        if !block_failed {
+                                            if block_input_device(&mut session, &mut device, keyboard, "keyboard", &reason, "command-pattern", &running) {
+                                                return Ok(());
+                                            }
+                                            block_failed = true;
+                                        }
+                                    }
                                 }
-                            } else {
-                                if backspace_found {
-                                    continue;
+                            }
+                        }
+                        if ev.value() == 1 { //check time difference 
+                            if let Some(verdict) = verdict {
+                                max_risk_since_report = max_risk_since_report.max(verdict.risk_score);
+                                if verdict.malicious {
+                                    discard_held_input(hold.take(), device_name);
+                                    if !block_failed {
+                                        if block_input_device(&mut session, &mut device, keyboard, "keyboard", &verdict.describe(), "keystroke-timing", &running) {
+                                            return Ok(());
+                                        }
+                                        block_failed = true;
+                                    }
                                 }
-                                else{
-                                    log_file.write_all(b"\n")?;
-                                    log_file.flush()?;
-                                    backspace_found = true;
+                                else if !first_verdict_logged {
+                                    first_verdict_logged = true;
+                                    judged_benign = true;
+                                    push_log(format!("[RESULT] The device was scanned, not a BAD USB ({}), monitoring continues", verdict.describe()));
+                                    session.event(&format!("first verdict {}", verdict.describe()));
+                                    session.set_verdict(&format!("benign, risk {:.2}", verdict.risk_score), false);
                                 }
+                                last_verdict = Some(verdict);
                             }
                         }
                     }
                 }
+                if let Some(input_hold) = hold.as_mut() {
+                    // Keys typed before the code, and the code itself, never reach the system
+                    if challenge_passed {
+                        input_hold.discard();
+                        if let Err(e) = input_hold.release() {
+                            push_log(format!("[ERROR] Failed to release keyboard {}: {}", device_name, e));
+                        }
+                        continue;
+                    }
+                    if let Err(e) = input_hold.push(&events) {
+                        push_log(format!("[ERROR] Failed to forward input of keyboard {}: {}", device_name, e));
+                    }
+                    if input_hold.is_holding() && challenge.is_none() && !judged_benign {
+                        held_key_presses += events
+                            .iter()
+                            .filter(|ev| matches!(ev.kind(), InputEventKind::Key(_)) && ev.value() == 1)
+                            .count();
+                        // Slow typing gives no verdict, the keyboard is never released without one
+                        if held_key_presses >= hold_config.prompt_after_key_presses {
+                            held_key_presses = 0;
+                            let dropped = input_hold.discard();
+                            prompt_unjudged_keyboard(device_name, dropped);
+                            session.event(&format!("no verdict yet, {} held back events were dropped", dropped));
+                        }
+                    }
+                    if judged_benign && input_hold.is_holding() && challenge.is_none() {
+                        match input_hold.release() {
+                            Ok(replayed) => push_log(format!("[ACTION] Keyboard {} was released, {} held back events were replayed", device_name, replayed)),
+                            Err(e) => push_log(format!("[ERROR] Failed to replay input of keyboard {}: {}", device_name, e)),
+                        }
+                    }
+                }
             }
+            // Signal interrupted the wait, nothing is wrong with the device
+            Err(Errno::EINTR) => continue,
             Err(e) => {
-                push_log(format!("poll failed: {}", e));
+                push_log(format!("[ERROR] Poll of keyboard {} failed: {}, monitoring stopped", device_name, e));
                 break;
             }
         }
     }
+    // Input that was never judged does not reach the system
+    discard_held_input(hold, device_name);
     Ok(())
 }
 
+/* Asks the user to type on a held keyboard that got no verdict yet, its input was dropped */
+fn prompt_unjudged_keyboard(device_name: &str, dropped: usize) {
+    push_log(format!("[WARNING] Keyboard {} is still held without a verdict, {} held back events were dropped. Type a few words on it without pausing to get it judged", device_name, dropped));
+    Notification::new()
+        .summary("Keyboard input held back")
+        .body(&format!("Keyboard {} was not judged yet and its input was dropped. Type a few words on it without pausing", device_name))
+        .icon("dialog-warning")
+        .show()
+        .ok();
+}
 
-fn unmount_device(sysfs_device_path: &str) -> std::io::Result<()> {
-    // Datapath example: /sys/bus/usb/devices/2-1/authorized. 
-    // When changing option in authorised the device could not then communicate with OS
-    let authorized_file = format!("/sys/bus/usb/devices/{}/authorized", sysfs_device_path);
-
-    // Writing 0 to disable device
-    let mut file = OpenOptions::new()
-        .write(true)
-        .open(&authorized_file)?;
+/* Shows the verification code in the TUI and as a desktop notification */
+fn announce_challenge(device_name: &str, code: &str) {
+    push_log(format!("[CHALLENGE] Type {} on the new keyboard {} and press Enter to allow it", code, device_name));
+    Notification::new()
+        .summary("New keyboard connected")
+        .body(&format!("Type {} on the keyboard {} and press Enter to allow it", code, device_name))
+        .icon("dialog-information")
+        .show()
+        .ok();
+}
 
-    file.write_all(b"0")?;
+/* Adds the keyboard that passed the challenge to the whitelist */
+fn whitelist_keyboard(device_path: &str, policy: MatchPolicy) {
+    let identity = udev::Device::from_subsystem_sysname("usb".to_string(), device_path.to_string())
+        .ok()
+        .and_then(|device| DeviceIdentity::from_udev(&device));
+    match identity {
+        Some(identity) => {
+            let description = identity.describe();
+            if whitelist_device_main(identity, policy, None) {
+                push_log(format!("[ACTION] Added {} to whitelist", description));
+            }
+        }
+        None => push_log(format!("[ERROR] Keyboard {} could not be added to whitelist, it is not a USB device", device_path)),
+    }
+}
 
-    push_log(format!("[ACTION] Power off for {} device (authorized=0)", sysfs_device_path));
-    Ok(())
+/* Throws away the input that was held back, nothing of it reached the system */
+fn discard_held_input(hold: Option<InputHold>, device_name: &str) {
+    if let Some(mut hold) = hold {
+        if hold.is_holding() {
+            push_log(format!("[ACTION] {} held back events of keyboard {} were discarded", hold.discard(), device_name));
+        }
+    }
 }
 
+/* Built-in device that was judged malicious is only disconnected when the user asks for it */
+fn ask_to_disconnect(monitored: &MonitoredDevice, kind: &str, reason: &str) {
+    let device_name = &monitored.name;
+    let transport = &monitored.transport;
+    match transport.binding() {
+        Some(binding) => {
+            push_log(format!(
+                "[ALERT] {} {} {} looks malicious ({}), it was attached before LockDown and stays connected. \
+                 Type 'disconnect {}' to unbind its {}",
+                transport, kind, device_name, reason, monitored.devnode, binding.describe()
+            ));
+            PENDING_DISCONNECTS.lock().unwrap().push(PendingDisconnect {
+                devnode: monitored.devnode.clone(),
+                name: device_name.clone(),
+                binding: binding.clone(),
+            });
+        }
+        None => push_log(format!(
+            "[ALERT] {} {} {} looks malicious ({}), it was attached before LockDown and stays connected",
+            transport, kind, device_name, reason
+        )),
+    }
+    Notification::new()
+        .summary("Input Device Alert")
+        .body(&format!("The {} {} looks malicious, see RustGuardian to disconnect it \n", kind, device_name))
+        .icon("dialog-warning")
+        .show()
+        .ok();
+}
 
+/// Unbinds the driver of a built-in device that was judged malicious, after the user asked for it
+pub fn disconnect_pending(devnode: &str) -> Result<String, String> {
+    let mut pending = PENDING_DISCONNECTS.lock().unwrap();
+    let position = pending
+        .iter()
+        .position(|device| device.devnode == devnode)
+        .ok_or_else(|| format!("No device {} is waiting to be disconnected", devnode))?;
+    let device = pending.remove(position);
+    device.binding.unbind().map_err(|e| format!("Failed to unbind {}: {}", device.binding.describe(), e))?;
+    Ok(format!("Unbound {} of {}", device.binding.describe(), device.name))
+}
 
+/* Disables the keyboard or pointer device (kind) after one of the detectors registered an attack.
+   Returns false when the device is still connected (the response failed or the user is asked to disconnect it), its monitoring goes on */
+pub fn block_input_device(
+    session: &mut SessionLog,
+    device: &mut Device,
+    monitored: &MonitoredDevice,
+    kind: &str,
+    reason: &str,
+    detector: &str,
+    running: &AtomicBool,
+) -> bool {
+    let device_name = monitored.name.as_str();
+    let transport = &monitored.transport;
+    // Keys held for the verdict are written before the event, so the entries stay in order
+    session.set_verdict(&format!("malicious, {}: {}", detector, reason), true);
+    session.event(&format!("BadUSB detected by {} on {} {}: {}", detector, transport, kind, reason));
+    // Built-in keyboard of a laptop could not be connected again without a reboot
+    if monitored.plugged_in.is_none() && transport.is_built_in() {
+        session.event("attached before LockDown, the user is asked to disconnect it");
+        ask_to_disconnect(monitored, kind, reason);
+        return false;
+    }
+    push_log(format!("[WARNING] RustGuardian registered BadUSB attack on {} {} ({}), the device will be unmounted", transport, kind, reason));
+    match transport {
+        InputTransport::Usb { port_path } => {
+            if let Err(e) = block_device(port_path, device_name, reason, detector) {
+                push_log(format!("[ERROR] Failed to disconnect USB {} {}: {}", kind, device_name, e));
+                return ignore_device(device, kind, device_name, running);
+            }
+            // Send notification
+            Notification::new()
+                .summary("USB Device Alert")
+                .body(&format!("Registered BadUSB attack, the device {} will be disconnected \n", device_name))
+                .icon("dialog-warning")
+                .show()
+                .ok();
+            push_log(format!("[ACTION] Device was succesfully removed"));
+        }
+        InputTransport::Virtual { owners } => {
+            // Process that created the virtual device is killed, the device disappears with it
+            let owners: Vec<&UinputOwner> = owners.iter().filter(|owner| owner.still_owns_uinput()).collect();
+            if let [owner] = owners.as_slice() {
+                if let Err(e) = kill_process(owner.pid) {
+                    push_log(format!("[ERROR] Failed to kill process {}: {}", owner.describe(), e));
+                    return ignore_device(device, kind, device_name, running);
+                }
+                session.event(&format!("killed process {}", owner.describe()));
+                Notification::new()
+                    .summary("Input Injection Alert")
+                    .body(&format!("Process {} injected input through the virtual {} {} and was killed", owner.name, kind, device_name))
+                    .icon("dialog-warning")
+                    .show()
+                    .ok();
+                push_log(format!("[ACTION] Process {} that injected input was killed", owner.describe()));
+            } else {
+                push_log(format!("[WARNING] Process that created the virtual {} {} is not known ({} candidates)", kind, device_name, owners.len()));
+                return ignore_device(device, kind, device_name, running);
+            }
+        }
+        InputTransport::Bluetooth { .. } | InputTransport::I2c { .. } | InputTransport::Serial { .. } => {
+            // These devices have no authorized attribute, their HID driver is detached instead
+            let unbound = match transport.binding() {
+                Some(binding) => match binding.unbind() {
+                    Ok(()) => {
+                        push_log(format!("[ACTION] Unbound {} of {} {} {}", binding.describe(), transport, kind, device_name));
+                        true
+                    }
+                    Err(e) => {
+                        push_log(format!("[ERROR] Failed to unbind {}: {}", binding.describe(), e));
+                        false
+                    }
+                },
+                None => false,
+            };
+            if unbound {
+                Notification::new()
+                    .summary("Input Device Alert")
+                    .body(&format!("Registered BadUSB attack, the {} {} {} was disconnected \n", transport, kind, device_name))
+                    .icon("dialog-warning")
+                    .show()
+                    .ok();
+            } else {
+                return ignore_device(device, kind, device_name, running);
+            }
+        }
+        InputTransport::Unknown => {
+            push_log(format!("[WARNING] The {} {} could not be disconnected, its transport is not known", kind, device_name));
+            return ignore_device(device, kind, device_name, running);
+        }
+    }
+    true
+}
 
-/* Hashmap to write the text as user inputs it */
-fn create_keymap() -> HashMap<Key, &'static str> {
-    HashMap::from([
-        (Key::KEY_A, "a"), (Key::KEY_B, "b"), (Key::KEY_C, "c"), (Key::KEY_D, "d"),
-        (Key::KEY_E, "e"), (Key::KEY_F, "f"), (Key::KEY_G, "g"), (Key::KEY_H, "h"),
-        (Key::KEY_I, "i"), (Key::KEY_J, "j"), (Key::KEY_K, "k"), (Key::KEY_L, "l"),
-        (Key::KEY_M, "m"), (Key::KEY_N, "n"), (Key::KEY_O, "o"), (Key::KEY_P, "p"),
-        (Key::KEY_Q, "q"), (Key::KEY_R, "r"), (Key::KEY_S, "s"), (Key::KEY_T, "t"),
-        (Key::KEY_U, "u"), (Key::KEY_V, "v"), (Key::KEY_W, "w"), (Key::KEY_X, "x"),
-        (Key::KEY_Y, "y"), (Key::KEY_Z, "z"),
-        (Key::KEY_1, "1"), (Key::KEY_2, "2"), (Key::KEY_3, "3"), (Key::KEY_4, "4"),
-        (Key::KEY_5, "5"), (Key::KEY_6, "6"), (Key::KEY_7, "7"), (Key::KEY_8, "8"),
-        (Key::KEY_9, "9"), (Key::KEY_0, "0"),
-        (Key::KEY_SPACE, " "), (Key::KEY_ENTER, "\n"),
-        (Key::KEY_MINUS, "-"), (Key::KEY_EQUAL, "="),
-        (Key::KEY_LEFTBRACE, "["), (Key::KEY_RIGHTBRACE, "]"),
-        (Key::KEY_BACKSLASH, "\\"), (Key::KEY_SEMICOLON, ";"),
-        (Key::KEY_APOSTROPHE, "'"), (Key::KEY_GRAVE, "`"),
-        (Key::KEY_COMMA, ","), (Key::KEY_DOT, "."), (Key::KEY_SLASH, "/"),
-        (Key::KEY_TAB, "\t"), (Key::KEY_BACKSPACE, "\0"),
-    ])
-}
\ No newline at end of file
+/* Keeps the device grabbed and throws its input away until it disappears or LockDown stops.
+   Returns false when the device could not be grabbed, the user is asked to unplug it and it stays monitored */
+fn ignore_device(device: &mut Device, kind: &str, device_name: &str, running: &AtomicBool) -> bool {
+    if let Err(e) = device.grab() {
+        push_log(format!("[ERROR] Failed to grab {} {}: {}, it stays connected and monitored", kind, device_name, e));
+        Notification::new()
+            .summary("Input Injection Alert")
+            .body(&format!("Registered input injection, but the {} {} could not be blocked. Unplug it \n", kind, device_name))
+            .icon("dialog-warning")
+            .show()
+            .ok();
+        return false;
+    }
+    push_log(format!("[ACTION] Input of {} {} is grabbed and ignored", kind, device_name));
+    Notification::new()
+        .summary("Input Injection Alert")
+        .body(&format!("Registered input injection, the input of {} is ignored \n", device_name))
+        .icon("dialog-warning")
+        .show()
+        .ok();
+    let mut poll_fds = [PollFd::new(unsafe{BorrowedFd::borrow_raw(device.as_raw_fd())}, PollFlags::POLLIN)];
+    while running.load(Ordering::Relaxed) {
+        match poll(&mut poll_fds, 100u16) {
+            Ok(0) => continue,
+            Ok(_) => {
+                if device.fetch_events().map(|events| events.count()).is_err() {
+                    break;
+                }
+            }
+            Err(Errno::EINTR) => continue,
+            Err(e) => {
+                push_log(format!("[ERROR] Poll of {} {} failed: {}, its input is not ignored anymore", kind, device_name, e));
+                break;
+            }
+        }
+    }
+    true
+}
@@ -0,0 +1,434 @@
+use std::fmt;
+use evdev::Key;
+use serde::{Deserialize, Serialize};
+
+// Value of the evdev key event
+const KEY_UP: i32 = 0;
+const KEY_DOWN: i32 = 1;
+const KEY_AUTOREPEAT: i32 = 2;
+
+/// Keyboard layout used to turn key codes into characters, same names as in XKB
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyboardLayout {
+    #[default]
+    Us,
+    Uk,
+    De,
+    Sk,
+    Cz,
+}
+
+impl fmt::Display for KeyboardLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            KeyboardLayout::Us => "us",
+            KeyboardLayout::Uk => "uk",
+            KeyboardLayout::De => "de",
+            KeyboardLayout::Sk => "sk",
+            KeyboardLayout::Cz => "cz",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl KeyboardLayout {
+    // AltGr on the US layout is a normal Alt key
+    fn has_altgr(&self) -> bool {
+        *self != KeyboardLayout::Us
+    }
+}
+
+/// What a key press produced
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodedKey {
+    /// Printable character
+    Char(char),
+    Enter,
+    Backspace,
+    /// Special key or chord, for example <SUPER+R>, <CTRL+ALT+T>, <UP> or <F5>
+    Token(String),
+}
+
+/// Decodes key events of one device into characters and tokens.
+/// Keeps the state of shift, caps lock, AltGr and the chord modifiers, so `|`, `>`, `$`
+/// and upper case paths are decoded the same way the system sees them
+pub struct KeyDecoder {
+    layout: KeyboardLayout,
+    left_shift: bool,
+    right_shift: bool,
+    caps_lock: bool,
+    ctrl: bool,
+    alt: bool,
+    altgr: bool,
+    meta: bool,
+    // Super pressed and released alone opens the application menu, so it is logged as <SUPER>
+    meta_used: bool,
+}
+
+impl KeyDecoder {
+    pub fn new(layout: KeyboardLayout) -> Self {
+        KeyDecoder {
+            layout,
+            left_shift: false,
+            right_shift: false,
+            caps_lock: false,
+            ctrl: false,
+            alt: false,
+            altgr: false,
+            meta: false,
+            meta_used: false,
+        }
+    }
+
+    pub fn layout(&self) -> KeyboardLayout {
+        self.layout
+    }
+
+    /// Feeds the key event (value 0 up, 1 down, 2 autorepeat), returns what the key produced
+    pub fn key_event(&mut self, key: Key, value: i32) -> Option<DecodedKey> {
+        let pressed = value != KEY_UP;
+        match key {
+            Key::KEY_LEFTSHIFT => self.left_shift = pressed,
+            Key::KEY_RIGHTSHIFT => self.right_shift = pressed,
+            Key::KEY_LEFTCTRL | Key::KEY_RIGHTCTRL => self.ctrl = pressed,
+            Key::KEY_LEFTALT => self.alt = pressed,
+            Key::KEY_RIGHTALT if self.layout.has_altgr() => self.altgr = pressed,
+            Key::KEY_RIGHTALT => self.alt = pressed,
+            Key::KEY_LEFTMETA | Key::KEY_RIGHTMETA => {
+                if value == KEY_DOWN {
+                    self.meta = true;
+                    self.meta_used = false;
+                } else if value == KEY_UP {
+                    self.meta = false;
+                    if !self.meta_used {
+                        return Some(DecodedKey::Token("<SUPER>".to_string()));
+                    }
+                }
+            }
+            // Only the press toggles caps lock, autorepeat does not
+            Key::KEY_CAPSLOCK if value == KEY_DOWN => self.caps_lock = !self.caps_lock,
+            Key::KEY_CAPSLOCK => {}
+            _ if value == KEY_DOWN || value == KEY_AUTOREPEAT => return self.key_press(key),
+            _ => {}
+        }
+        None
+    }
+
+    fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    fn key_press(&mut self, key: Key) -> Option<DecodedKey> {
+        self.meta_used = self.meta;
+        let shift = self.shift();
+        if self.ctrl || self.alt || self.meta {
+            // Chords are written with the key as it is labeled on the layout
+            let name = match self.character(key, false, false) {
+                Some(' ') => "SPACE".to_string(),
+                Some('\t') => "TAB".to_string(),
+                Some(c) => c.to_uppercase().to_string(),
+                None => key_name(key),
+            };
+            let mut chord = Vec::new();
+            if self.ctrl { chord.push("CTRL".to_string()); }
+            if self.alt { chord.push("ALT".to_string()); }
+            if shift { chord.push("SHIFT".to_string()); }
+            if self.meta { chord.push("SUPER".to_string()); }
+            chord.push(name);
+            return Some(DecodedKey::Token(format!("<{}>", chord.join("+"))));
+        }
+        match key {
+            Key::KEY_ENTER | Key::KEY_KPENTER => return Some(DecodedKey::Enter),
+            Key::KEY_BACKSPACE => return Some(DecodedKey::Backspace),
+            _ => {}
+        }
+        if let Some(c) = self.character(key, shift, self.altgr) {
+            // Caps lock changes only letters, shift together with caps lock gives lower case again
+            let c = if self.caps_lock && c.is_alphabetic() { swap_case(c) } else { c };
+            return Some(DecodedKey::Char(c));
+        }
+        let name = key_name(key);
+        Some(DecodedKey::Token(if shift { format!("<SHIFT+{}>", name) } else { format!("<{}>", name) }))
+    }
+
+    // Character of the key on the layout for the shift and AltGr level
+    fn character(&self, key: Key, shift: bool, altgr: bool) -> Option<char> {
+        if let Some(c) = keypad_character(key) {
+            return Some(c);
+        }
+        if altgr {
+            return altgr_character(self.layout, key);
+        }
+        let (normal, shifted) = layout_characters(self.layout, key)?;
+        Some(if shift { shifted } else { normal })
+    }
+}
+
+fn swap_case(c: char) -> char {
+    if c.is_lowercase() {
+        c.to_uppercase().next().unwrap_or(c)
+    } else {
+        c.to_lowercase().next().unwrap_or(c)
+    }
+}
+
+// Name of a key that does not produce a character
+fn key_name(key: Key) -> String {
+    let name = match key {
+        Key::KEY_ESC => "ESC",
+        Key::KEY_ENTER | Key::KEY_KPENTER => "ENTER",
+        Key::KEY_BACKSPACE => "BACKSPACE",
+        Key::KEY_UP => "UP",
+        Key::KEY_DOWN => "DOWN",
+        Key::KEY_LEFT => "LEFT",
+        Key::KEY_RIGHT => "RIGHT",
+        Key::KEY_HOME => "HOME",
+        Key::KEY_END => "END",
+        Key::KEY_PAGEUP => "PAGEUP",
+        Key::KEY_PAGEDOWN => "PAGEDOWN",
+        Key::KEY_INSERT => "INSERT",
+        Key::KEY_DELETE => "DELETE",
+        Key::KEY_SYSRQ => "PRINT",
+        Key::KEY_COMPOSE => "MENU",
+        Key::KEY_F1 => "F1", Key::KEY_F2 => "F2", Key::KEY_F3 => "F3", Key::KEY_F4 => "F4",
+        Key::KEY_F5 => "F5", Key::KEY_F6 => "F6", Key::KEY_F7 => "F7", Key::KEY_F8 => "F8",
+        Key::KEY_F9 => "F9", Key::KEY_F10 => "F10", Key::KEY_F11 => "F11", Key::KEY_F12 => "F12",
+        _ => return format!("{:?}", key).trim_start_matches("KEY_").to_string(),
+    };
+    name.to_string()
+}
+
+// Keypad gives the same characters on all layouts, num lock is expected to be on
+fn keypad_character(key: Key) -> Option<char> {
+    let c = match key {
+        Key::KEY_KP0 => '0', Key::KEY_KP1 => '1', Key::KEY_KP2 => '2', Key::KEY_KP3 => '3', Key::KEY_KP4 => '4',
+        Key::KEY_KP5 => '5', Key::KEY_KP6 => '6', Key::KEY_KP7 => '7', Key::KEY_KP8 => '8', Key::KEY_KP9 => '9',
+        Key::KEY_KPDOT => '.', Key::KEY_KPSLASH => '/', Key::KEY_KPASTERISK => '*',
+        Key::KEY_KPMINUS => '-', Key::KEY_KPPLUS => '+',
+        _ => return None,
+    };
+    Some(c)
+}
+
+// Normal and shifted character of the key, keys the layout does not change come from the US layout.
+// Dead keys are written as their own character, they are not combined with the next key
+fn layout_characters(layout: KeyboardLayout, key: Key) -> Option<(char, char)> {
+    let characters = match layout {
+        KeyboardLayout::Us => None,
+        KeyboardLayout::Uk => match key {
+            Key::KEY_2 => Some(('2', '"')),
+            Key::KEY_3 => Some(('3', '£')),
+            Key::KEY_APOSTROPHE => Some(('\'', '@')),
+            Key::KEY_GRAVE => Some(('`', '¬')),
+            Key::KEY_BACKSLASH => Some(('#', '~')),
+            Key::KEY_102ND => Some(('\\', '|')),
+            _ => None,
+        },
+        KeyboardLayout::De => match key {
+            Key::KEY_Y => Some(('z', 'Z')),
+            Key::KEY_Z => Some(('y', 'Y')),
+            Key::KEY_2 => Some(('2', '"')),
+            Key::KEY_3 => Some(('3', '§')),
+            Key::KEY_6 => Some(('6', '&')),
+            Key::KEY_7 => Some(('7', '/')),
+            Key::KEY_8 => Some(('8', '(')),
+            Key::KEY_9 => Some(('9', ')')),
+            Key::KEY_0 => Some(('0', '=')),
+            Key::KEY_MINUS => Some(('ß', '?')),
+            Key::KEY_EQUAL => Some(('´', '`')),
+            Key::KEY_LEFTBRACE => Some(('ü', 'Ü')),
+            Key::KEY_RIGHTBRACE => Some(('+', '*')),
+            Key::KEY_SEMICOLON => Some(('ö', 'Ö')),
+            Key::KEY_APOSTROPHE => Some(('ä', 'Ä')),
+            Key::KEY_GRAVE => Some(('^', '°')),
+            Key::KEY_BACKSLASH => Some(('#', '\'')),
+            Key::KEY_COMMA => Some((',', ';')),
+            Key::KEY_DOT => Some(('.', ':')),
+            Key::KEY_SLASH => Some(('-', '_')),
+            Key::KEY_102ND => Some(('<', '>')),
+            _ => None,
+        },
+        KeyboardLayout::Sk | KeyboardLayout::Cz => match key {
+            Key::KEY_Y => Some(('z', 'Z')),
+            Key::KEY_Z => Some(('y', 'Y')),
+            Key::KEY_1 => Some(('+', '1')),
+            Key::KEY_2 if layout == KeyboardLayout::Sk => Some(('ľ', '2')),
+            Key::KEY_2 => Some(('ě', '2')),
+            Key::KEY_3 => Some(('š', '3')),
+            Key::KEY_4 => Some(('č', '4')),
+            Key::KEY_5 if layout == KeyboardLayout::Sk => Some(('ť', '5')),
+            Key::KEY_5 => Some(('ř', '5')),
+            Key::KEY_6 => Some(('ž', '6')),
+            Key::KEY_7 => Some(('ý', '7')),
+            Key::KEY_8 => Some(('á', '8')),
+            Key::KEY_9 => Some(('í', '9')),
+            Key::KEY_0 => Some(('é', '0')),
+            Key::KEY_MINUS => Some(('=', '%')),
+            Key::KEY_EQUAL => Some(('´', 'ˇ')),
+            Key::KEY_LEFTBRACE => Some(('ú', '/')),
+            Key::KEY_RIGHTBRACE if layout == KeyboardLayout::Sk => Some(('ä', '(')),
+            Key::KEY_RIGHTBRACE => Some((')', '(')),
+            Key::KEY_SEMICOLON if layout == KeyboardLayout::Sk => Some(('ô', '"')),
+            Key::KEY_SEMICOLON => Some(('ů', '"')),
+            Key::KEY_APOSTROPHE => Some(('§', '!')),
+            Key::KEY_GRAVE => Some((';', '°')),
+            Key::KEY_BACKSLASH if layout == KeyboardLayout::Sk => Some(('ň', ')')),
+            Key::KEY_BACKSLASH => Some(('¨', '\'')),
+            Key::KEY_COMMA => Some((',', '?')),
+            Key::KEY_DOT => Some(('.', ':')),
+            Key::KEY_SLASH => Some(('-', '_')),
+            Key::KEY_102ND if layout == KeyboardLayout::Sk => Some(('&', '*')),
+            Key::KEY_102ND => Some(('\\', '|')),
+            _ => None,
+        },
+    };
+    characters.or_else(|| us_characters(key))
+}
+
+// Third level characters of the layout, typed with AltGr
+fn altgr_character(layout: KeyboardLayout, key: Key) -> Option<char> {
+    let c = match layout {
+        KeyboardLayout::Us => return None,
+        KeyboardLayout::Uk => match key {
+            Key::KEY_4 => '€',
+            Key::KEY_GRAVE => '¦',
+            _ => return None,
+        },
+        KeyboardLayout::De => match key {
+            Key::KEY_2 => '²', Key::KEY_3 => '³', Key::KEY_7 => '{', Key::KEY_8 => '[',
+            Key::KEY_9 => ']', Key::KEY_0 => '}', Key::KEY_MINUS => '\\', Key::KEY_RIGHTBRACE => '~',
+            Key::KEY_Q => '@', Key::KEY_E => '€', Key::KEY_M => 'µ', Key::KEY_102ND => '|',
+            _ => return None,
+        },
+        KeyboardLayout::Sk | KeyboardLayout::Cz => match key {
+            Key::KEY_1 => '~', Key::KEY_2 => 'ˇ', Key::KEY_3 => '^', Key::KEY_4 => '˘', Key::KEY_5 => '°',
+            Key::KEY_6 => '˛', Key::KEY_7 => '`', Key::KEY_8 => '˙', Key::KEY_9 => '´', Key::KEY_0 => '˝',
+            Key::KEY_Q => '\\', Key::KEY_W => '|', Key::KEY_E => '€', Key::KEY_F => '[', Key::KEY_G => ']',
+            Key::KEY_X => '#', Key::KEY_C => '&', Key::KEY_V => '@', Key::KEY_B => '{', Key::KEY_N => '}',
+            Key::KEY_LEFTBRACE => '÷', Key::KEY_RIGHTBRACE => '×', Key::KEY_SEMICOLON => '$',
+            Key::KEY_APOSTROPHE => 'ß', Key::KEY_COMMA => '<', Key::KEY_DOT => '>', Key::KEY_SLASH => '*',
+            _ => return None,
+        },
+    };
+    Some(c)
+}
+
+// Characters of the US layout
+fn us_characters(key: Key) -> Option<(char, char)> {
+    let characters = match key {
+        Key::KEY_A => ('a', 'A'), Key::KEY_B => ('b', 'B'), Key::KEY_C => ('c', 'C'), Key::KEY_D => ('d', 'D'),
+        Key::KEY_E => ('e', 'E'), Key::KEY_F => ('f', 'F'), Key::KEY_G => ('g', 'G'), Key::KEY_H => ('h', 'H'),
+        Key::KEY_I => ('i', 'I'), Key::KEY_J => ('j', 'J'), Key::KEY_K => ('k', 'K'), Key::KEY_L => ('l', 'L'),
+        Key::KEY_M => ('m', 'M'), Key::KEY_N => ('n', 'N'), Key::KEY_O => ('o', 'O'), Key::KEY_P => ('p', 'P'),
+        Key::KEY_Q => ('q', 'Q'), Key::KEY_R => ('r', 'R'), Key::KEY_S => ('s', 'S'), Key::KEY_T => ('t', 'T'),
+        Key::KEY_U => ('u', 'U'), Key::KEY_V => ('v', 'V'), Key::KEY_W => ('w', 'W'), Key::KEY_X => ('x', 'X'),
+        Key::KEY_Y => ('y', 'Y'), Key::KEY_Z => ('z', 'Z'),
+        Key::KEY_1 => ('1', '!'), Key::KEY_2 => ('2', '@'), Key::KEY_3 => ('3', '#'), Key::KEY_4 => ('4', '$'),
+        Key::KEY_5 => ('5', '%'), Key::KEY_6 => ('6', '^'), Key::KEY_7 => ('7', '&'), Key::KEY_8 => ('8', '*'),
+        Key::KEY_9 => ('9', '('), Key::KEY_0 => ('0', ')'),
+        Key::KEY_SPACE => (' ', ' '), Key::KEY_TAB => ('\t', '\t'),
+        Key::KEY_MINUS => ('-', '_'), Key::KEY_EQUAL => ('=', '+'),
+        Key::KEY_LEFTBRACE => ('[', '{'), Key::KEY_RIGHTBRACE => (']', '}'),
+        Key::KEY_BACKSLASH => ('\\', '|'), Key::KEY_SEMICOLON => (';', ':'),
+        Key::KEY_APOSTROPHE => ('\'', '"'), Key::KEY_GRAVE => ('`', '~'),
+        Key::KEY_COMMA => (',', '<'), Key::KEY_DOT => ('.', '>'), Key::KEY_SLASH => ('/', '?'),
+        Key::KEY_102ND => ('\\', '|'),
+        _ => return None,
+    };
+    Some(characters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Presses the key with shift or AltGr held, like a keyboard sends it
+    fn decode(layout: KeyboardLayout, shift: bool, altgr: bool, key: Key) -> Option<DecodedKey> {
+        let mut decoder = KeyDecoder::new(layout);
+        if shift {
+            decoder.key_event(Key::KEY_LEFTSHIFT, KEY_DOWN);
+        }
+        if altgr {
+            decoder.key_event(Key::KEY_RIGHTALT, KEY_DOWN);
+        }
+        let decoded = decoder.key_event(key, KEY_DOWN);
+        decoder.key_event(key, KEY_UP);
+        decoded
+    }
+
+    // Characters the command rules look for: pipes, paths, options, quotes and variables
+    fn assert_layout(layout: KeyboardLayout, expected: &[(char, bool, bool, Key)]) {
+        for (c, shift, altgr, key) in expected {
+            assert_eq!(
+                decode(layout, *shift, *altgr, *key),
+                Some(DecodedKey::Char(*c)),
+                "{} on layout {} (shift {}, AltGr {})",
+                c, layout, shift, altgr
+            );
+        }
+    }
+
+    #[test]
+    fn us_layout() {
+        assert_layout(KeyboardLayout::Us, &[
+            ('|', true, false, Key::KEY_BACKSLASH),
+            ('/', false, false, Key::KEY_SLASH),
+            ('-', false, false, Key::KEY_MINUS),
+            ('\'', false, false, Key::KEY_APOSTROPHE),
+            ('"', true, false, Key::KEY_APOSTROPHE),
+            ('$', true, false, Key::KEY_4),
+        ]);
+        // Right Alt is a plain Alt on the US layout
+        assert_eq!(decode(KeyboardLayout::Us, false, true, Key::KEY_T), Some(DecodedKey::Token("<ALT+T>".to_string())));
+    }
+
+    #[test]
+    fn uk_layout() {
+        assert_layout(KeyboardLayout::Uk, &[
+            ('|', true, false, Key::KEY_102ND),
+            ('/', false, false, Key::KEY_SLASH),
+            ('-', false, false, Key::KEY_MINUS),
+            ('\'', false, false, Key::KEY_APOSTROPHE),
+            ('"', true, false, Key::KEY_2),
+            ('$', true, false, Key::KEY_4),
+        ]);
+    }
+
+    #[test]
+    fn de_layout() {
+        assert_layout(KeyboardLayout::De, &[
+            ('|', false, true, Key::KEY_102ND),
+            ('/', true, false, Key::KEY_7),
+            ('-', false, false, Key::KEY_SLASH),
+            ('\'', true, false, Key::KEY_BACKSLASH),
+            ('"', true, false, Key::KEY_2),
+            ('$', true, false, Key::KEY_4),
+        ]);
+    }
+
+    #[test]
+    fn sk_layout() {
+        assert_layout(KeyboardLayout::Sk, &[
+            ('|', false, true, Key::KEY_W),
+            ('/', true, false, Key::KEY_LEFTBRACE),
+            ('-', false, false, Key::KEY_SLASH),
+            ('"', true, false, Key::KEY_SEMICOLON),
+            ('$', false, true, Key::KEY_SEMICOLON),
+            ('4', true, false, Key::KEY_4),
+        ]);
+    }
+
+    #[test]
+    fn cz_layout() {
+        assert_layout(KeyboardLayout::Cz, &[
+            ('|', false, true, Key::KEY_W),
+            ('/', true, false, Key::KEY_LEFTBRACE),
+            ('-', false, false, Key::KEY_SLASH),
+            ('\'', true, false, Key::KEY_BACKSLASH),
+            ('"', true, false, Key::KEY_SEMICOLON),
+            ('$', false, true, Key::KEY_SEMICOLON),
+            ('4', true, false, Key::KEY_4),
+        ]);
+    }
+}
@@ -0,0 +1,456 @@
+use std::collections::VecDeque;
+use evdev::Key;
+use super::config::DetectorConfig;
+use super::user_session::UserActivity;
+
+// Value of the evdev key event
+const KEY_UP: i32 = 0;
+const KEY_DOWN: i32 = 1;
+const KEY_AUTOREPEAT: i32 = 2;
+// A key typed into a locked session counts less than one typed into an idle session,
+// the user types the password into the lock screen
+const LOCKED_KEY_WEIGHT: f64 = 0.5;
+
+/// Feature vector computed from the keystrokes of the window
+#[derive(Clone, Debug, Default)]
+pub struct KeystrokeFeatures {
+    pub intervals: usize,
+    /// Average time between two key presses
+    pub average_interval_ms: f64,
+    pub interval_variance: f64,
+    /// Intervals within the jitter tolerance of the average
+    pub uniform_intervals: usize,
+    /// Intervals shorter than the minimal human interval
+    pub too_fast_intervals: usize,
+    /// How long the keys were held down
+    pub hold_mean_ms: f64,
+    pub hold_variance: f64,
+    /// Time between releasing a key and pressing the next one, negative when they overlap
+    pub flight_mean_ms: f64,
+    pub flight_variance: f64,
+    /// Share of key presses that happened while another key was still held
+    pub overlap_ratio: f64,
+    /// Share of modifier holds where the modifier was pressed clearly before the key it modifies.
+    /// Injected HID reports press the modifier and the key at the same moment
+    pub modifier_overlap_ratio: f64,
+    /// Autorepeat events of the session, humans hold keys, scripts usually do not
+    pub autorepeats: usize,
+    /// Time from plugging the keyboard in to its first key, unknown for keyboards attached before LockDown
+    pub first_key_delay_ms: Option<f64>,
+    /// Key presses that came before a human could reach the keyboard after plugging it in
+    pub keys_before_reach: usize,
+    /// Key presses of the window typed while the user session was idle or locked
+    pub inactive_keys: usize,
+    /// Part of `inactive_keys` typed while the session was locked
+    pub locked_keys: usize,
+}
+
+impl KeystrokeFeatures {
+    pub fn describe(&self) -> String {
+        let mut description = format!(
+            "interval {:.1}±{:.1} ms ({} of {} uniform, {} too fast), hold {:.1}±{:.1} ms, flight {:.1}±{:.1} ms, overlap {:.2}, modifier overlap {:.2}, autorepeats {}",
+            self.average_interval_ms,
+            self.interval_variance.sqrt(),
+            self.uniform_intervals,
+            self.intervals,
+            self.too_fast_intervals,
+            self.hold_mean_ms,
+            self.hold_variance.sqrt(),
+            self.flight_mean_ms,
+            self.flight_variance.sqrt(),
+            self.overlap_ratio,
+            self.modifier_overlap_ratio,
+            self.autorepeats
+        );
+        if let Some(delay) = self.first_key_delay_ms {
+            description.push_str(&format!(", first key {:.0} ms after plug-in, {} keys before reach", delay, self.keys_before_reach));
+        }
+        if self.inactive_keys > 0 {
+            description.push_str(&format!(
+                ", {} keys while the session was idle or locked ({} locked)",
+                self.inactive_keys, self.locked_keys
+            ));
+        }
+        description
+    }
+}
+
+/// Result of one evaluation of the keystroke window
+#[derive(Clone, Debug)]
+pub struct KeystrokeVerdict {
+    /// From 0 (human) to 1 (injected)
+    pub risk_score: f64,
+    pub malicious: bool,
+    pub features: KeystrokeFeatures,
+}
+
+impl KeystrokeVerdict {
+    pub fn describe(&self) -> String {
+        format!("risk {:.2}: {}", self.risk_score, self.features.describe())
+    }
+}
+
+// Single key press inside of the window
+struct Keystroke {
+    key: Key,
+    is_modifier: bool,
+    down_ms: u128,
+    up_ms: Option<u128>,
+    overlapped: bool,
+    activity: UserActivity,
+}
+
+// Modifier that is held right now
+struct ModifierHold {
+    key: Key,
+    down_ms: u128,
+    first_key_down_ms: Option<u128>,
+}
+
+pub fn is_modifier(key: Key) -> bool {
+    matches!(
+        key,
+        Key::KEY_LEFTSHIFT | Key::KEY_RIGHTSHIFT | Key::KEY_LEFTCTRL | Key::KEY_RIGHTCTRL
+            | Key::KEY_LEFTALT | Key::KEY_RIGHTALT | Key::KEY_LEFTMETA | Key::KEY_RIGHTMETA
+    )
+}
+
+fn mean_and_variance(values: &[f64]) -> (f64, f64) {
+    if values.is_empty() {
+        return (0.0, 0.0);
+    }
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance = values.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / values.len() as f64;
+    (mean, variance)
+}
+
+/// Sliding window detector that runs for the whole life of the device.
+/// BadUSB types with almost the same interval between keys, holds every key for the same time
+/// and never presses a key before the previous one is released, humans do all of it differently.
+pub struct KeystrokeDetector {
+    config: DetectorConfig,
+    window: VecDeque<Keystroke>,
+    modifiers_held: Vec<ModifierHold>,
+    /// For the last modifier holds, true when the modifier was pressed clearly before the key
+    modifier_holds: VecDeque<bool>,
+    autorepeats: usize,
+    /// When the keyboard was plugged in, from udev
+    plugged_in_ms: Option<u128>,
+    first_key_delay_ms: Option<f64>,
+    keys_before_reach: usize,
+    /// Key presses since the detector was created
+    key_presses: usize,
+    user_activity: UserActivity,
+}
+
+impl KeystrokeDetector {
+    pub fn new(config: DetectorConfig) -> Self {
+        KeystrokeDetector {
+            window: VecDeque::with_capacity(config.window_size),
+            modifiers_held: Vec::new(),
+            modifier_holds: VecDeque::with_capacity(config.window_size),
+            autorepeats: 0,
+            plugged_in_ms: None,
+            first_key_delay_ms: None,
+            keys_before_reach: 0,
+            key_presses: 0,
+            user_activity: UserActivity::Unknown,
+            config,
+        }
+    }
+
+    /// Time the keyboard was plugged in (ms since the epoch like the key timestamps).
+    /// BadUSB starts typing as soon as it is enumerated, a human has to reach the keyboard first
+    pub fn set_plugged_in(&mut self, timestamp_ms: u128) {
+        self.plugged_in_ms = Some(timestamp_ms);
+    }
+
+    /// State of the user session, used for the next key presses
+    pub fn set_user_activity(&mut self, activity: UserActivity) {
+        self.user_activity = activity;
+    }
+
+    fn window_size(&self) -> usize {
+        self.config.window_size.max(2)
+    }
+
+    /// Adds the key event (value 0 up, 1 down, 2 autorepeat, timestamp in ms)
+    /// and evaluates the window on every key press once it is full
+    pub fn key_event(&mut self, key: Key, value: i32, timestamp_ms: u128) -> Option<KeystrokeVerdict> {
+        match value {
+            KEY_DOWN => self.key_down(key, timestamp_ms),
+            KEY_UP => {
+                self.key_up(key, timestamp_ms);
+                None
+            }
+            KEY_AUTOREPEAT => {
+                self.autorepeats += 1;
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn key_down(&mut self, key: Key, timestamp_ms: u128) -> Option<KeystrokeVerdict> {
+        self.key_presses += 1;
+        if let Some(plugged_in_ms) = self.plugged_in_ms {
+            let delay_ms = timestamp_ms.saturating_sub(plugged_in_ms) as f64;
+            self.first_key_delay_ms.get_or_insert(delay_ms);
+            if delay_ms < self.config.human_reach_ms {
+                self.keys_before_reach += 1;
+            }
+        }
+        if let Some(last) = self.window.back() {
+            // After a long pause the old keys say nothing about the new burst
+            if timestamp_ms.saturating_sub(last.down_ms) as f64 > self.config.pause_reset_ms {
+                self.window.clear();
+            }
+        }
+        let is_modifier = is_modifier(key);
+        if is_modifier {
+            self.modifiers_held.push(ModifierHold { key, down_ms: timestamp_ms, first_key_down_ms: None });
+        } else {
+            for hold in self.modifiers_held.iter_mut() {
+                hold.first_key_down_ms.get_or_insert(timestamp_ms);
+            }
+        }
+        let overlapped = !is_modifier
+            && self.window.iter().any(|keystroke| !keystroke.is_modifier && keystroke.up_ms.is_none());
+        self.window.push_back(Keystroke {
+            key,
+            is_modifier,
+            down_ms: timestamp_ms,
+            up_ms: None,
+            overlapped,
+            activity: self.user_activity,
+        });
+        if self.window.len() > self.window_size() {
+            self.window.pop_front();
+        }
+        if self.window.len() < self.window_size() {
+            return None;
+        }
+        Some(self.evaluate())
+    }
+
+    fn key_up(&mut self, key: Key, timestamp_ms: u128) {
+        if let Some(keystroke) = self
+            .window
+            .iter_mut()
+            .rev()
+            .find(|keystroke| keystroke.key == key && keystroke.up_ms.is_none())
+        {
+            keystroke.up_ms = Some(timestamp_ms);
+        }
+        if let Some(position) = self.modifiers_held.iter().position(|hold| hold.key == key) {
+            let hold = self.modifiers_held.remove(position);
+            // Only holds that modified some key say something about the typist
+            if let Some(first_key_down_ms) = hold.first_key_down_ms {
+                let lead_ms = first_key_down_ms.saturating_sub(hold.down_ms) as f64;
+                self.modifier_holds.push_back(lead_ms >= self.config.min_interval_ms);
+                if self.modifier_holds.len() > self.window_size() {
+                    self.modifier_holds.pop_front();
+                }
+            }
+        }
+    }
+
+    fn features(&self) -> KeystrokeFeatures {
+        let keystrokes: Vec<&Keystroke> = self.window.iter().collect();
+        let intervals: Vec<f64> = keystrokes
+            .windows(2)
+            .map(|pair| pair[1].down_ms.saturating_sub(pair[0].down_ms) as f64)
+            .collect();
+        let (average_interval_ms, interval_variance) = mean_and_variance(&intervals);
+        let uniform_intervals = intervals
+            .iter()
+            .filter(|interval| (*interval - average_interval_ms).abs() < self.config.jitter_tolerance_ms)
+            .count();
+        let too_fast_intervals = intervals
+            .iter()
+            .filter(|interval| **interval < self.config.min_interval_ms)
+            .count();
+
+        let holds: Vec<f64> = keystrokes
+            .iter()
+            .filter_map(|keystroke| keystroke.up_ms.map(|up_ms| up_ms.saturating_sub(keystroke.down_ms) as f64))
+            .collect();
+        let (hold_mean_ms, hold_variance) = mean_and_variance(&holds);
+        let flights: Vec<f64> = keystrokes
+            .windows(2)
+            .filter_map(|pair| pair[0].up_ms.map(|up_ms| pair[1].down_ms as f64 - up_ms as f64))
+            .collect();
+        let (flight_mean_ms, flight_variance) = mean_and_variance(&flights);
+
+        let regular_keys = keystrokes.iter().filter(|keystroke| !keystroke.is_modifier).count();
+        let overlapped_keys = keystrokes.iter().filter(|keystroke| keystroke.overlapped).count();
+        let overlap_ratio = if regular_keys > 0 { overlapped_keys as f64 / regular_keys as f64 } else { 0.0 };
+        let modifier_overlap_ratio = if self.modifier_holds.is_empty() {
+            0.0
+        } else {
+            self.modifier_holds.iter().filter(|human| **human).count() as f64 / self.modifier_holds.len() as f64
+        };
+
+        KeystrokeFeatures {
+            intervals: intervals.len(),
+            average_interval_ms,
+            interval_variance,
+            uniform_intervals,
+            too_fast_intervals,
+            hold_mean_ms,
+            hold_variance,
+            flight_mean_ms,
+            flight_variance,
+            overlap_ratio,
+            modifier_overlap_ratio,
+            autorepeats: self.autorepeats,
+            first_key_delay_ms: self.first_key_delay_ms,
+            keys_before_reach: self.keys_before_reach,
+            inactive_keys: keystrokes.iter().filter(|keystroke| keystroke.activity.is_inactive()).count(),
+            locked_keys: keystrokes.iter().filter(|keystroke| keystroke.activity == UserActivity::Locked).count(),
+        }
+    }
+
+    // Risk from the moment of typing instead of its rhythm, 0 when nothing about it is known
+    fn context_risk(&self, features: &KeystrokeFeatures) -> f64 {
+        let idle_keys = features.inactive_keys - features.locked_keys;
+        let inactive_risk = (idle_keys as f64 + features.locked_keys as f64 * LOCKED_KEY_WEIGHT)
+            / self.window.len().max(1) as f64;
+        // Plug-in time only says something about the first window, a human typing later is not judged by it
+        if self.key_presses > self.window_size() {
+            return inactive_risk;
+        }
+        let reach_ms = self.config.human_reach_ms.max(1.0);
+        // Full risk before a human could type, none after twice that time
+        let first_key_risk = features
+            .first_key_delay_ms
+            .map_or(0.0, |delay| (2.0 - delay / reach_ms).clamp(0.0, 1.0));
+        let burst_risk = (features.keys_before_reach as f64 / self.window_size() as f64).min(1.0);
+        first_key_risk.max(burst_risk).max(inactive_risk)
+    }
+
+    fn evaluate(&self) -> KeystrokeVerdict {
+        let features = self.features();
+        let intervals = features.intervals.max(1) as f64;
+        // Same interval between all keys, or keys faster than a human could press them
+        let timing_risk = (features.uniform_intervals as f64 / intervals)
+            .max(features.too_fast_intervals as f64 / intervals);
+        // Every key held for the same time, unknown until at least two keys were released
+        let hold_risk = if self.window.iter().filter(|keystroke| keystroke.up_ms.is_some()).count() >= 2 {
+            (1.0 - features.hold_variance.sqrt() / self.config.hold_jitter_ms).clamp(0.0, 1.0)
+        } else {
+            timing_risk
+        };
+        // Keys never overlap each other or modifiers
+        let overlap_risk = 1.0 - features.overlap_ratio.max(features.modifier_overlap_ratio);
+
+        let weights = self.config.timing_weight + self.config.hold_weight + self.config.overlap_weight;
+        let risk_score = if weights > 0.0 {
+            (timing_risk * self.config.timing_weight
+                + hold_risk * self.config.hold_weight
+                + overlap_risk * self.config.overlap_weight)
+                / weights
+        } else {
+            timing_risk
+        };
+        // Typing right after plug-in or into a locked session only adds to the risk, it never lowers it
+        let context_risk = self.context_risk(&features);
+        let risk_score = if self.config.context_weight > 0.0 && weights > 0.0 {
+            let with_context = (risk_score * weights + context_risk * self.config.context_weight)
+                / (weights + self.config.context_weight);
+            risk_score.max(with_context)
+        } else {
+            risk_score
+        };
+        KeystrokeVerdict {
+            risk_score,
+            malicious: risk_score >= self.config.risk_threshold,
+            features,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: [Key; 7] = [Key::KEY_H, Key::KEY_E, Key::KEY_L, Key::KEY_O, Key::KEY_W, Key::KEY_R, Key::KEY_D];
+
+    // Types the keys with the given press intervals and hold durations, returns the last verdict
+    fn type_keys(detector: &mut KeystrokeDetector, start_ms: u128, intervals: &[u128], holds: &[u128]) -> Option<KeystrokeVerdict> {
+        let mut verdict = None;
+        let mut down_ms = start_ms;
+        for (index, key) in KEYS.iter().enumerate() {
+            down_ms += intervals[index % intervals.len()];
+            verdict = detector.key_event(*key, KEY_DOWN, down_ms).or(verdict);
+            detector.key_event(*key, KEY_UP, down_ms + holds[index % holds.len()]);
+        }
+        verdict
+    }
+
+    const HUMAN_INTERVALS: [u128; 7] = [180, 95, 240, 130, 310, 150, 205];
+    const HUMAN_HOLDS: [u128; 7] = [85, 110, 70, 130, 95, 60, 120];
+
+    #[test]
+    fn scripted_uniform_input_is_malicious() {
+        let mut detector = KeystrokeDetector::new(DetectorConfig::default());
+        let verdict = type_keys(&mut detector, 0, &[20], &[8]).expect("window is full");
+        assert!(verdict.malicious, "{}", verdict.describe());
+        assert_eq!(verdict.features.uniform_intervals, verdict.features.intervals);
+    }
+
+    #[test]
+    fn human_jitter_is_not_malicious() {
+        let mut detector = KeystrokeDetector::new(DetectorConfig::default());
+        let verdict = type_keys(&mut detector, 0, &HUMAN_INTERVALS, &HUMAN_HOLDS).expect("window is full");
+        assert!(!verdict.malicious, "{}", verdict.describe());
+    }
+
+    #[test]
+    fn pause_starts_a_new_window() {
+        let mut detector = KeystrokeDetector::new(DetectorConfig::default());
+        assert!(type_keys(&mut detector, 0, &[20], &[8]).is_some());
+        // Keys after the pause are judged alone, once there are enough of them
+        let after_pause = 140 + 1600;
+        for (index, key) in KEYS.iter().enumerate() {
+            let down_ms = after_pause + index as u128 * 20;
+            let verdict = detector.key_event(*key, KEY_DOWN, down_ms);
+            detector.key_event(*key, KEY_UP, down_ms + 8);
+            assert_eq!(verdict.is_some(), index == KEYS.len() - 1);
+        }
+    }
+
+    #[test]
+    fn plug_in_time_only_counts_for_the_first_window() {
+        let mut plugged_in = KeystrokeDetector::new(DetectorConfig::default());
+        plugged_in.set_plugged_in(0);
+        let mut attached_before = KeystrokeDetector::new(DetectorConfig::default());
+        let first = type_keys(&mut plugged_in, 0, &HUMAN_INTERVALS, &HUMAN_HOLDS).unwrap();
+        let baseline = type_keys(&mut attached_before, 0, &HUMAN_INTERVALS, &HUMAN_HOLDS).unwrap();
+        // Human typing right after plug-in looks suspicious only in the first window
+        assert!(first.risk_score > baseline.risk_score);
+        assert_eq!(first.features.first_key_delay_ms, Some(180.0));
+
+        let later = type_keys(&mut plugged_in, 2000, &HUMAN_INTERVALS, &HUMAN_HOLDS).unwrap();
+        let later_baseline = type_keys(&mut attached_before, 2000, &HUMAN_INTERVALS, &HUMAN_HOLDS).unwrap();
+        assert_eq!(later.risk_score, later_baseline.risk_score);
+    }
+
+    fn human_typing_risk(activity: UserActivity) -> f64 {
+        let mut detector = KeystrokeDetector::new(DetectorConfig::default());
+        detector.set_user_activity(activity);
+        type_keys(&mut detector, 0, &HUMAN_INTERVALS, &HUMAN_HOLDS)
+            .expect("window is full")
+            .risk_score
+    }
+
+    #[test]
+    fn locked_session_adds_less_risk_than_idle_session() {
+        assert!(UserActivity::Locked.is_inactive());
+        let active = human_typing_risk(UserActivity::Active);
+        let locked = human_typing_risk(UserActivity::Locked);
+        let idle = human_typing_risk(UserActivity::Idle);
+        assert!(active < locked, "active {} locked {}", active, locked);
+        assert!(locked < idle, "locked {} idle {}", locked, idle);
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::Hmac;
+use lazy_static::lazy_static;
+use sha2::Sha256;
+use super::config::{config, resolve_path};
+use super::storage::{create_temp_file, invalid_data, write_atomically};
+
+// Start of every encrypted log, the last byte is the format version
+const MAGIC: &[u8; 6] = b"RGLOG\x01";
+const KEY_LENGTH: usize = 32;
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 12;
+// Magic, key source, salt and PBKDF2 rounds
+const PRELUDE_LENGTH: usize = MAGIC.len() + 1 + SALT_LENGTH + 4;
+const PBKDF2_ROUNDS: u32 = 600_000;
+// Records longer than this are not written by RustGuardian, the file is broken
+const MAX_RECORD_LENGTH: usize = 1 << 20;
+
+const SOURCE_KEY_FILE: u8 = 0;
+const SOURCE_PASSPHRASE: u8 = 1;
+
+// Passphrase, salt and rounds the key was derived from
+type DerivationInput = (String, [u8; SALT_LENGTH], u32);
+
+lazy_static! {
+    // Keys derived from the passphrase, PBKDF2 runs only once for every file
+    static ref DERIVED_KEYS: Mutex<HashMap<DerivationInput, [u8; KEY_LENGTH]>> = Mutex::new(HashMap::new());
+}
+
+/// Where the key of the encrypted logs comes from
+#[derive(Clone, Debug)]
+pub enum LogKeySource {
+    /// File with the key as 64 hex characters
+    KeyFile(PathBuf),
+    /// Passphrase, the key is derived with PBKDF2 and the salt of each file
+    Passphrase(String),
+}
+
+impl LogKeySource {
+    /// Key source for new logs, None when plaintext logs were chosen
+    pub fn from_config() -> Option<Self> {
+        if config().log_encryption.plaintext {
+            return None;
+        }
+        Some(Self::configured())
+    }
+
+    /// Key source from the config, also used to read old encrypted logs in plaintext mode
+    pub fn configured() -> Self {
+        let settings = config().log_encryption;
+        match settings.passphrase {
+            Some(passphrase) if !passphrase.is_empty() => LogKeySource::Passphrase(passphrase),
+            _ => LogKeySource::KeyFile(resolve_path(&settings.key_file)),
+        }
+    }
+
+    fn kind(&self) -> u8 {
+        match self {
+            LogKeySource::KeyFile(_) => SOURCE_KEY_FILE,
+            LogKeySource::Passphrase(_) => SOURCE_PASSPHRASE,
+        }
+    }
+
+    // Key for the file with this salt, a missing key file is created only when writing
+    fn key(&self, salt: &[u8; SALT_LENGTH], rounds: u32, create: bool) -> io::Result<[u8; KEY_LENGTH]> {
+        match self {
+            LogKeySource::KeyFile(path) => load_key_file(path, create),
+            LogKeySource::Passphrase(passphrase) => {
+                let cache_key = (passphrase.clone(), *salt, rounds);
+                if let Some(key) = DERIVED_KEYS.lock().unwrap().get(&cache_key) {
+                    return Ok(*key);
+                }
+                let mut key = [0u8; KEY_LENGTH];
+                pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, rounds, &mut key);
+                DERIVED_KEYS.lock().unwrap().insert(cache_key, key);
+                Ok(key)
+            }
+        }
+    }
+}
+
+pub fn fill_random(buffer: &mut [u8]) -> io::Result<()> {
+    File::open("/dev/urandom")?.read_exact(buffer)
+}
+
+fn load_key_file(path: &Path, create: bool) -> io::Result<[u8; KEY_LENGTH]> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && create => return create_key_file(path),
+        Err(e) => return Err(e),
+    };
+    let text = text.trim();
+    let invalid_key = || invalid_data(format!("key in {} must be {} hex characters", path.display(), KEY_LENGTH * 2));
+    if text.len() != KEY_LENGTH * 2 || !text.is_ascii() {
+        return Err(invalid_key());
+    }
+    let mut key = [0u8; KEY_LENGTH];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).map_err(|_| invalid_key())?;
+    }
+    Ok(key)
+}
+
+// First encrypted log, the key is generated and only root can read it.
+// The key is complete before it appears under its name, and when another session created the file first its key is used
+fn create_key_file(path: &Path) -> io::Result<[u8; KEY_LENGTH]> {
+    let mut key = [0u8; KEY_LENGTH];
+    fill_random(&mut key)?;
+    let hex: String = key.iter().map(|byte| format!("{:02x}", byte)).collect();
+    // AlreadyExists means a session file name collision to the caller, key file errors must not look like one
+    let key_file_error = |e: io::Error| io::Error::other(format!("failed to create key file {}: {}", path.display(), e));
+    let (tmp_path, mut file) = create_temp_file(path, 0o600).map_err(key_file_error)?;
+    let linked = writeln!(file, "{}", hex).and_then(|_| file.sync_all()).and_then(|_| fs::hard_link(&tmp_path, path));
+    let _ = fs::remove_file(&tmp_path);
+    match linked {
+        Ok(()) => {
+            crate::push_log(format!("[INFO] New key for encrypted logs was written to {}", path.display()));
+            Ok(key)
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => load_key_file(path, false),
+        Err(e) => Err(key_file_error(e)),
+    }
+}
+
+// Position of the record is authenticated too, so records could not be removed or reordered
+fn associated_data(salt: &[u8], index: u64) -> Vec<u8> {
+    let mut data = Vec::with_capacity(MAGIC.len() + SALT_LENGTH + 8);
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(salt);
+    data.extend_from_slice(&index.to_be_bytes());
+    data
+}
+
+fn encrypt_record(cipher: &ChaCha20Poly1305, salt: &[u8], index: u64, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    fill_random(&mut nonce)?;
+    let aad = associated_data(salt, index);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &aad })
+        .map_err(|_| io::Error::other("encryption failed"))?;
+    let mut record = Vec::with_capacity(4 + NONCE_LENGTH + ciphertext.len());
+    record.extend_from_slice(&((NONCE_LENGTH + ciphertext.len()) as u32).to_be_bytes());
+    record.extend_from_slice(&nonce);
+    record.extend_from_slice(&ciphertext);
+    Ok(record)
+}
+
+struct Prelude {
+    source: u8,
+    salt: [u8; SALT_LENGTH],
+    rounds: u32,
+}
+
+fn parse_prelude(data: &[u8]) -> io::Result<Prelude> {
+    if data.len() < PRELUDE_LENGTH || &data[..MAGIC.len()] != MAGIC {
+        return Err(invalid_data("not an encrypted RustGuardian log"));
+    }
+    let mut salt = [0u8; SALT_LENGTH];
+    salt.copy_from_slice(&data[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LENGTH]);
+    let rounds_start = MAGIC.len() + 1 + SALT_LENGTH;
+    let rounds = u32::from_be_bytes(data[rounds_start..PRELUDE_LENGTH].try_into().unwrap());
+    // Every log is written with the same rounds, anything else would let a changed file make PBKDF2 run for hours
+    if rounds != PBKDF2_ROUNDS {
+        return Err(invalid_data(format!("log uses {} PBKDF2 rounds instead of {}", rounds, PBKDF2_ROUNDS)));
+    }
+    Ok(Prelude { source: data[MAGIC.len()], salt, rounds })
+}
+
+// Splits the records after the prelude into (nonce, ciphertext) without decrypting them
+fn split_records(data: &[u8]) -> io::Result<Vec<&[u8]>> {
+    let mut records = Vec::new();
+    let mut position = PRELUDE_LENGTH;
+    while position < data.len() {
+        if data.len() - position < 4 {
+            return Err(invalid_data("log ends in the middle of a record"));
+        }
+        let length = u32::from_be_bytes(data[position..position + 4].try_into().unwrap()) as usize;
+        position += 4;
+        if !(NONCE_LENGTH..=MAX_RECORD_LENGTH).contains(&length) || data.len() - position < length {
+            return Err(invalid_data("log ends in the middle of a record"));
+        }
+        records.push(&data[position..position + length]);
+        position += length;
+    }
+    Ok(records)
+}
+
+/// Log file where every record is encrypted and authenticated with ChaCha20-Poly1305
+pub struct EncryptedLogWriter {
+    path: PathBuf,
+    file: File,
+    cipher: ChaCha20Poly1305,
+    salt: [u8; SALT_LENGTH],
+    next_index: u64,
+}
+
+impl EncryptedLogWriter {
+    pub fn create(path: &Path, source: &LogKeySource) -> io::Result<Self> {
+        let mut salt = [0u8; SALT_LENGTH];
+        fill_random(&mut salt)?;
+        let key = source.key(&salt, PBKDF2_ROUNDS, true)?;
+        let mut file = OpenOptions::new().create_new(true).write(true).mode(0o600).open(path)?;
+        let mut prelude = Vec::with_capacity(PRELUDE_LENGTH);
+        prelude.extend_from_slice(MAGIC);
+        prelude.push(source.kind());
+        prelude.extend_from_slice(&salt);
+        prelude.extend_from_slice(&PBKDF2_ROUNDS.to_be_bytes());
+        file.write_all(&prelude)?;
+        Ok(EncryptedLogWriter {
+            path: path.to_path_buf(),
+            file,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            salt,
+            next_index: 0,
+        })
+    }
+
+    /// Encrypts the text and appends it as the next record
+    pub fn append(&mut self, plaintext: &str) -> io::Result<()> {
+        let record = encrypt_record(&self.cipher, &self.salt, self.next_index, plaintext.as_bytes())?;
+        self.file.write_all(&record)?;
+        self.next_index += 1;
+        Ok(())
+    }
+
+    /// Replaces the first record (the session header), the other records are copied as they are
+    pub fn rewrite_first(&mut self, plaintext: &str) -> io::Result<()> {
+        self.file.sync_all()?;
+        let data = fs::read(&self.path)?;
+        let records = split_records(&data)?;
+        let mut updated = data[..PRELUDE_LENGTH].to_vec();
+        updated.extend_from_slice(&encrypt_record(&self.cipher, &self.salt, 0, plaintext.as_bytes())?);
+        for record in records.iter().skip(1) {
+            updated.extend_from_slice(&(record.len() as u32).to_be_bytes());
+            updated.extend_from_slice(record);
+        }
+        write_atomically(&self.path, &updated, 0o600)?;
+        // Old file was replaced, later records go to the new one
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// True when the file starts like an encrypted log
+pub fn is_encrypted_log(path: &Path) -> bool {
+    let mut magic = [0u8; MAGIC.len()];
+    File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && &magic == MAGIC
+}
+
+/// Decrypts all records of the log, fails when any record was changed, removed or reordered
+pub fn read_encrypted_log(path: &Path, source: &LogKeySource) -> io::Result<Vec<String>> {
+    read_encrypted_records(path, source, usize::MAX)
+}
+
+/// Decrypts only the first records, used to read session headers.
+/// The file is read only as far as the records are needed
+pub fn read_encrypted_records(path: &Path, source: &LogKeySource, limit: usize) -> io::Result<Vec<String>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut prelude_data = [0u8; PRELUDE_LENGTH];
+    reader
+        .read_exact(&mut prelude_data)
+        .map_err(|e| if e.kind() == io::ErrorKind::UnexpectedEof { invalid_data("not an encrypted RustGuardian log") } else { e })?;
+    let prelude = parse_prelude(&prelude_data)?;
+    if prelude.source != source.kind() {
+        let expected = if prelude.source == SOURCE_PASSPHRASE { "a passphrase" } else { "a key file" };
+        return Err(invalid_data(format!("log was encrypted with {}", expected)));
+    }
+    let key = source.key(&prelude.salt, prelude.rounds, false)?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+    let mut lines = Vec::new();
+    let mut index = 0;
+    while index < limit {
+        let Some(record) = read_record(&mut reader)? else {
+            break;
+        };
+        let (nonce, ciphertext) = record.split_at(NONCE_LENGTH);
+        let aad = associated_data(&prelude.salt, index as u64);
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_| invalid_data(format!("record {} could not be decrypted, wrong key or the log was changed", index)))?;
+        lines.push(String::from_utf8_lossy(&plaintext).into_owned());
+        index += 1;
+    }
+    Ok(lines)
+}
+
+// Next (nonce, ciphertext) record of the file, None at its end
+fn read_record(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    let mut filled = 0;
+    while filled < length.len() {
+        match reader.read(&mut length[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(invalid_data("log ends in the middle of a record")),
+            read => filled += read,
+        }
+    }
+    let length = u32::from_be_bytes(length) as usize;
+    if !(NONCE_LENGTH..=MAX_RECORD_LENGTH).contains(&length) {
+        return Err(invalid_data("log ends in the middle of a record"));
+    }
+    let mut record = vec![0u8; length];
+    reader
+        .read_exact(&mut record)
+        .map_err(|e| if e.kind() == io::ErrorKind::UnexpectedEof { invalid_data("log ends in the middle of a record") } else { e })?;
+    Ok(Some(record))
+}
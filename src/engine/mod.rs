@@ -2,4 +2,23 @@ pub mod keylogger;
 pub mod find_device;
 pub mod process_checker;
 pub mod whitelist;
-pub mod filehash;
\ No newline at end of file
+pub mod filehash;
+pub mod usb_auth;
+pub mod storage;
+pub mod quarantine;
+pub mod usb_monitor;
+pub mod config;
+pub mod keystroke_analysis;
+pub mod command_rules;
+pub mod keymap;
+pub mod input_hold;
+pub mod challenge;
+pub mod virtual_input;
+pub mod transport;
+pub mod session_log;
+pub mod log_crypto;
+pub mod user_session;
+pub mod pointer;
+pub mod scan_jobs;
+pub mod hash_index;
+pub mod feeds;
\ No newline at end of file
@@ -0,0 +1,460 @@
+use evdev::{AbsoluteAxisType, Device, InputEvent, InputEventKind, Key, RelativeAxisType, Synchronization};
+use std::collections::VecDeque;
+use std::io;
+use std::os::fd::BorrowedFd;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use crate::push_log;
+use super::config::{config, PointerConfig};
+use super::keylogger::{block_input_device, MonitoredDevice};
+use super::session_log::{sessions_dir, SessionLog};
+
+// Value of the evdev key event
+const BUTTON_UP: i32 = 0;
+const BUTTON_DOWN: i32 = 1;
+// Clicks needed before their timing says something
+const MIN_CLICKS: usize = 3;
+// Teleports in the window that make the device certainly scripted
+const TELEPORTS_FOR_FULL_RISK: f64 = 2.0;
+
+/// Feature vector computed from the movements and clicks of the window
+#[derive(Clone, Debug, Default)]
+pub struct PointerFeatures {
+    pub moves: usize,
+    /// Movements longer than one unit that repeat the previous one exactly or exactly reverse it
+    pub repeated_moves: usize,
+    /// Distance covered by the repeated movements
+    pub repeated_distance: i64,
+    /// Intervals between the starts of the last movement bursts
+    pub burst_intervals: usize,
+    pub burst_period_ms: f64,
+    pub burst_period_jitter_ms: f64,
+    /// Absolute jumps across the screen without any movement between
+    pub teleports: usize,
+    pub clicks: usize,
+    /// Clicks held shorter or following the previous click faster than a human could
+    pub fast_clicks: usize,
+}
+
+impl PointerFeatures {
+    pub fn describe(&self) -> String {
+        format!(
+            "{} of {} moves repeated over {} units, burst period {:.0}±{:.0} ms over {} bursts, {} teleports, {} of {} clicks too fast",
+            self.repeated_moves,
+            self.moves,
+            self.repeated_distance,
+            self.burst_period_ms,
+            self.burst_period_jitter_ms,
+            self.burst_intervals,
+            self.teleports,
+            self.fast_clicks,
+            self.clicks
+        )
+    }
+}
+
+/// Result of one evaluation of the pointer window
+#[derive(Clone, Debug)]
+pub struct PointerVerdict {
+    /// From 0 (human) to 1 (injected)
+    pub risk_score: f64,
+    pub malicious: bool,
+    pub features: PointerFeatures,
+}
+
+impl PointerVerdict {
+    pub fn describe(&self) -> String {
+        format!("risk {:.2}: {}", self.risk_score, self.features.describe())
+    }
+}
+
+// Movement of one event frame
+struct Move {
+    dx: i32,
+    dy: i32,
+    teleport: bool,
+}
+
+/// Sliding window detector of mice and tablets.
+/// Injected pointers move in perfectly straight or repeated steps, jigglers move in a fixed period,
+/// absolute pointers jump straight onto the button they click and scripts click faster than a finger
+pub struct PointerDetector {
+    config: PointerConfig,
+    moves: VecDeque<Move>,
+    last_move_ms: Option<u128>,
+    burst_starts: VecDeque<u128>,
+    /// Size of the absolute axes, teleports are measured against it
+    abs_range: Option<(f64, f64)>,
+    /// Absolute position, forgotten when the pen or finger is lifted
+    position: Option<(i32, i32)>,
+    buttons_down: Vec<(Key, u128)>,
+    last_click_ms: Option<u128>,
+    /// For the last clicks, true when the click was too fast for a human
+    clicks: VecDeque<bool>,
+    // Frame that is collected until SYN_REPORT
+    frame_rel: (i32, i32),
+    frame_abs: (Option<i32>, Option<i32>),
+    frame_changed: bool,
+}
+
+fn is_button(key: Key) -> bool {
+    matches!(key, Key::BTN_LEFT | Key::BTN_RIGHT | Key::BTN_MIDDLE | Key::BTN_SIDE | Key::BTN_EXTRA)
+}
+
+// Pen or finger entering or leaving the tablet, the next position is a jump on purpose
+fn is_proximity(key: Key) -> bool {
+    matches!(key, Key::BTN_TOUCH | Key::BTN_TOOL_PEN | Key::BTN_TOOL_FINGER | Key::BTN_TOOL_RUBBER | Key::BTN_TOOL_MOUSE)
+}
+
+impl PointerDetector {
+    pub fn new(config: PointerConfig) -> Self {
+        PointerDetector {
+            moves: VecDeque::with_capacity(config.window_size),
+            last_move_ms: None,
+            burst_starts: VecDeque::new(),
+            abs_range: None,
+            position: None,
+            buttons_down: Vec::new(),
+            last_click_ms: None,
+            clicks: VecDeque::new(),
+            frame_rel: (0, 0),
+            frame_abs: (None, None),
+            frame_changed: false,
+            config,
+        }
+    }
+
+    fn window_size(&self) -> usize {
+        self.config.window_size.max(2)
+    }
+
+    /// Size of the absolute X and Y axes of the device
+    pub fn set_abs_range(&mut self, x_range: i32, y_range: i32) {
+        self.abs_range = Some((x_range.max(1) as f64, y_range.max(1) as f64));
+    }
+
+    /// Adds the event and evaluates the window at the end of every frame that moved or clicked
+    pub fn event(&mut self, event: &InputEvent, timestamp_ms: u128) -> Option<PointerVerdict> {
+        match event.kind() {
+            InputEventKind::RelAxis(RelativeAxisType::REL_X) => self.frame_rel.0 += event.value(),
+            InputEventKind::RelAxis(RelativeAxisType::REL_Y) => self.frame_rel.1 += event.value(),
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_X) => self.frame_abs.0 = Some(event.value()),
+            InputEventKind::AbsAxis(AbsoluteAxisType::ABS_Y) => self.frame_abs.1 = Some(event.value()),
+            InputEventKind::Key(key) if is_proximity(key) => self.position = None,
+            InputEventKind::Key(key) if is_button(key) => {
+                self.button(key, event.value(), timestamp_ms);
+                self.frame_changed = true;
+            }
+            InputEventKind::Synchronization(Synchronization::SYN_REPORT) => return self.end_frame(timestamp_ms),
+            _ => {}
+        }
+        None
+    }
+
+    fn button(&mut self, key: Key, value: i32, timestamp_ms: u128) {
+        match value {
+            BUTTON_DOWN => {
+                let interval_ms = self.last_click_ms.map(|last| timestamp_ms.saturating_sub(last) as f64);
+                self.last_click_ms = Some(timestamp_ms);
+                self.buttons_down.push((key, timestamp_ms));
+                // Interval is judged now, the hold time when the button is released
+                if interval_ms.is_some_and(|interval| interval < self.config.min_click_interval_ms) {
+                    self.push_click(true);
+                    self.buttons_down.retain(|(down_key, _)| *down_key != key);
+                }
+            }
+            BUTTON_UP => {
+                if let Some(position) = self.buttons_down.iter().position(|(down_key, _)| *down_key == key) {
+                    let (_, down_ms) = self.buttons_down.remove(position);
+                    let hold_ms = timestamp_ms.saturating_sub(down_ms) as f64;
+                    self.push_click(hold_ms < self.config.min_click_hold_ms);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn push_click(&mut self, too_fast: bool) {
+        self.clicks.push_back(too_fast);
+        if self.clicks.len() > self.window_size() {
+            self.clicks.pop_front();
+        }
+    }
+
+    fn end_frame(&mut self, timestamp_ms: u128) -> Option<PointerVerdict> {
+        let (dx, dy) = std::mem::take(&mut self.frame_rel);
+        let mut movement = if dx != 0 || dy != 0 { Some(Move { dx, dy, teleport: false }) } else { None };
+        let (abs_x, abs_y) = std::mem::take(&mut self.frame_abs);
+        if abs_x.is_some() || abs_y.is_some() {
+            let previous = self.position;
+            let current = (
+                abs_x.or(previous.map(|(x, _)| x)).unwrap_or_default(),
+                abs_y.or(previous.map(|(_, y)| y)).unwrap_or_default(),
+            );
+            self.position = Some(current);
+            if let Some((x, y)) = previous {
+                let (dx, dy) = (current.0 - x, current.1 - y);
+                let teleport = self.abs_range.is_some_and(|(x_range, y_range)| {
+                    (dx.abs() as f64 / x_range).max(dy.abs() as f64 / y_range) >= self.config.teleport_fraction
+                });
+                movement = Some(Move { dx, dy, teleport });
+            }
+        }
+        let changed = std::mem::take(&mut self.frame_changed);
+        match movement {
+            Some(movement) => self.push_move(movement, timestamp_ms),
+            None if !changed => return None,
+            None => {}
+        }
+        let features = self.features();
+        let enough_data = features.moves >= self.window_size() || features.clicks >= MIN_CLICKS || features.teleports > 0;
+        if !enough_data {
+            return None;
+        }
+        Some(self.evaluate(features))
+    }
+
+    fn push_move(&mut self, movement: Move, timestamp_ms: u128) {
+        // Pause longer than the gap starts a new burst of movement
+        if self.last_move_ms.is_none_or(|last| timestamp_ms.saturating_sub(last) as f64 > self.config.burst_gap_ms) {
+            self.burst_starts.push_back(timestamp_ms);
+            if self.burst_starts.len() > self.config.min_bursts.max(2) + 1 {
+                self.burst_starts.pop_front();
+            }
+        }
+        self.last_move_ms = Some(timestamp_ms);
+        self.moves.push_back(movement);
+        if self.moves.len() > self.window_size() {
+            self.moves.pop_front();
+        }
+    }
+
+    fn features(&self) -> PointerFeatures {
+        let moves: Vec<&Move> = self.moves.iter().collect();
+        // Steps of one unit are slow movement or sensor jitter, they repeat by nature
+        let repeated: Vec<&Move> = moves
+            .windows(2)
+            .filter(|pair| {
+                pair[1].dx.abs().max(pair[1].dy.abs()) > 1
+                    && ((pair[1].dx == pair[0].dx && pair[1].dy == pair[0].dy)
+                        || (pair[1].dx == -pair[0].dx && pair[1].dy == -pair[0].dy))
+            })
+            .map(|pair| pair[1])
+            .collect();
+        let starts: Vec<u128> = self.burst_starts.iter().copied().collect();
+        let intervals: Vec<f64> = starts.windows(2).map(|pair| pair[1].saturating_sub(pair[0]) as f64).collect();
+        let (burst_period_ms, burst_variance) = if intervals.is_empty() {
+            (0.0, 0.0)
+        } else {
+            let mean = intervals.iter().sum::<f64>() / intervals.len() as f64;
+            let variance = intervals.iter().map(|interval| (interval - mean).powi(2)).sum::<f64>() / intervals.len() as f64;
+            (mean, variance)
+        };
+        PointerFeatures {
+            moves: moves.len(),
+            repeated_moves: repeated.len(),
+            repeated_distance: repeated.iter().map(|movement| i64::from(movement.dx.abs().max(movement.dy.abs()))).sum(),
+            burst_intervals: intervals.len(),
+            burst_period_ms,
+            burst_period_jitter_ms: burst_variance.sqrt(),
+            teleports: moves.iter().filter(|movement| movement.teleport).count(),
+            clicks: self.clicks.len(),
+            fast_clicks: self.clicks.iter().filter(|too_fast| **too_fast).count(),
+        }
+    }
+
+    fn evaluate(&self, features: PointerFeatures) -> PointerVerdict {
+        // Every step the same as the one before, or the same step back and forth, at a constant speed over a longer distance
+        let linear_risk = if features.moves >= self.window_size() && features.repeated_distance as f64 >= self.config.min_linear_distance {
+            features.repeated_moves as f64 / (features.moves - 1) as f64
+        } else {
+            0.0
+        };
+        // Bursts of movement starting in a fixed period
+        let periodic_risk = if features.burst_intervals >= self.config.min_bursts.max(2) && self.config.period_jitter_ms > 0.0 {
+            (1.0 - features.burst_period_jitter_ms / self.config.period_jitter_ms).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let teleport_risk = (features.teleports as f64 / TELEPORTS_FOR_FULL_RISK).min(1.0);
+        let click_risk = if features.clicks >= MIN_CLICKS {
+            features.fast_clicks as f64 / features.clicks as f64
+        } else {
+            0.0
+        };
+        // Each of them alone is a sign of a script
+        let risk_score = linear_risk.max(periodic_risk).max(teleport_risk).max(click_risk);
+        PointerVerdict {
+            risk_score,
+            malicious: risk_score >= self.config.risk_threshold,
+            features,
+        }
+    }
+}
+
+/* Watches a new mouse or tablet and blocks it the same way as a keyboard when it moves or clicks like a script */
+pub fn start_pointer_monitoring(pointer: &MonitoredDevice, running: Arc<AtomicBool>) -> io::Result<()> {
+    let device_name = pointer.name.as_str();
+    let mut device = Device::open(&pointer.devnode)?;
+    let device_fd = device.as_raw_fd();
+    let mut session = SessionLog::start(&sessions_dir(), pointer.session_header());
+    if session.is_recorded() {
+        push_log(format!("[INFO] Events of pointer device {} will be saved into {}", device_name, session.path().display()));
+    }
+    let mut detector = PointerDetector::new(config().pointer_detector);
+    let has_abs_axes = device
+        .supported_absolute_axes()
+        .is_some_and(|axes| axes.contains(AbsoluteAxisType::ABS_X) && axes.contains(AbsoluteAxisType::ABS_Y));
+    if has_abs_axes {
+        if let Ok(state) = device.get_abs_state() {
+            let x = state[AbsoluteAxisType::ABS_X.0 as usize];
+            let y = state[AbsoluteAxisType::ABS_Y.0 as usize];
+            detector.set_abs_range(x.maximum - x.minimum, y.maximum - y.minimum);
+            session.event(&format!("absolute pointer, axes {}x{}", x.maximum - x.minimum, y.maximum - y.minimum));
+        }
+    }
+    let mut first_verdict_logged = false;
+    // Device that could not be blocked stays monitored, the user was already alerted about it
+    let mut block_failed = false;
+    let mut poll_fds = [PollFd::new(unsafe { BorrowedFd::borrow_raw(device_fd) }, PollFlags::POLLIN)];
+
+    while running.load(Ordering::Relaxed) {
+        match poll(&mut poll_fds, 100u16) {
+            Ok(0) => continue,
+            Ok(_) => {
+                let events: Vec<InputEvent> = match device.fetch_events() {
+                    Ok(events) => events.collect(),
+                    Err(e) => {
+                        // Device was unplugged or blocked
+                        push_log(format!("[INFO] Pointer device {} is not available anymore ({}), monitoring stopped", device_name, e));
+                        break;
+                    }
+                };
+                for ev in &events {
+                    let now = ev.timestamp().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis();
+                    let verdict = match detector.event(ev, now) {
+                        Some(verdict) => verdict,
+                        None => continue,
+                    };
+                    if verdict.malicious {
+                        if !block_failed {
+                            if block_input_device(&mut session, &mut device, pointer, "pointer", &verdict.describe(), "pointer-injection", &running) {
+                                return Ok(());
+                            }
+                            block_failed = true;
+                        }
+                        continue;
+                    }
+                    if !first_verdict_logged {
+                        first_verdict_logged = true;
+                        push_log(format!("[RESULT] Pointer device {} was scanned, not injected ({}), monitoring continues", device_name, verdict.describe()));
+                        session.event(&format!("first verdict {}", verdict.describe()));
+                        session.set_verdict(&format!("benign, risk {:.2}", verdict.risk_score), false);
+                    }
+                }
+            }
+            // Signal interrupted the wait, nothing is wrong with the device
+            Err(Errno::EINTR) => continue,
+            Err(e) => {
+                push_log(format!("[ERROR] Poll of pointer device {} failed: {}, monitoring stopped", device_name, e));
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::EventType;
+
+    // Sends one relative movement frame, returns the verdict of the window
+    fn move_by(detector: &mut PointerDetector, dx: i32, dy: i32, timestamp_ms: u128) -> Option<PointerVerdict> {
+        detector.event(&InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_X.0, dx), timestamp_ms);
+        detector.event(&InputEvent::new(EventType::RELATIVE, RelativeAxisType::REL_Y.0, dy), timestamp_ms);
+        detector.event(&InputEvent::new(EventType::SYNCHRONIZATION, Synchronization::SYN_REPORT.0, 0), timestamp_ms)
+    }
+
+    // Sends one absolute position frame
+    fn move_to(detector: &mut PointerDetector, x: i32, y: i32, timestamp_ms: u128) -> Option<PointerVerdict> {
+        detector.event(&InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, x), timestamp_ms);
+        detector.event(&InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_Y.0, y), timestamp_ms);
+        detector.event(&InputEvent::new(EventType::SYNCHRONIZATION, Synchronization::SYN_REPORT.0, 0), timestamp_ms)
+    }
+
+    #[test]
+    fn human_movement_is_not_malicious() {
+        let mut detector = PointerDetector::new(PointerConfig::default());
+        // Bursts of uneven steps separated by uneven pauses
+        let steps = [(3, 1), (5, -2), (2, 0), (7, 3), (4, -1), (6, 2), (1, 1), (8, -3), (5, 0), (3, 2)];
+        let pauses = [450, 1200, 700, 2100, 900, 1600];
+        let mut now = 0;
+        let mut last_verdict = None;
+        for pause in pauses {
+            now += pause;
+            for (i, (dx, dy)) in steps.iter().enumerate() {
+                now += 8 + (i as u128 * 5) % 11;
+                last_verdict = move_by(&mut detector, *dx, *dy + (pause as i32 % 3), now).or(last_verdict);
+            }
+        }
+        let verdict = last_verdict.expect("window is full");
+        assert!(!verdict.malicious, "{}", verdict.describe());
+        assert_eq!(verdict.features.teleports, 0);
+    }
+
+    #[test]
+    fn jiggler_period_is_malicious() {
+        let mut detector = PointerDetector::new(PointerConfig::default());
+        // Small move there and back every second
+        let mut verdict = None;
+        for burst in 0..20u128 {
+            let start = burst * 1000;
+            move_by(&mut detector, 1, 0, start);
+            verdict = move_by(&mut detector, -1, 0, start + 10).or(verdict);
+        }
+        let verdict = verdict.expect("window is full");
+        assert!(verdict.malicious, "{}", verdict.describe());
+        assert!(verdict.features.burst_period_jitter_ms < 1.0);
+    }
+
+    #[test]
+    fn teleport_is_malicious_but_lifting_the_pen_is_not() {
+        let mut detector = PointerDetector::new(PointerConfig::default());
+        detector.set_abs_range(1000, 1000);
+        move_to(&mut detector, 100, 100, 0);
+        // Pen lifted and put down on the other side of the tablet
+        detector.event(&InputEvent::new(EventType::KEY, Key::BTN_TOUCH.code(), 0), 10);
+        assert!(move_to(&mut detector, 900, 900, 20).is_none());
+
+        move_to(&mut detector, 100, 900, 30);
+        let verdict = move_to(&mut detector, 900, 100, 40).expect("teleports give a verdict");
+        assert_eq!(verdict.features.teleports, 2);
+        assert!(verdict.malicious, "{}", verdict.describe());
+    }
+
+    #[test]
+    fn one_unit_steps_are_not_linear() {
+        // Slow movement repeats the same single unit step
+        let mut detector = PointerDetector::new(PointerConfig::default());
+        let mut verdict = None;
+        for i in 0..300u128 {
+            verdict = move_by(&mut detector, 1, 0, i * 8).or(verdict);
+        }
+        let verdict = verdict.expect("window is full");
+        assert_eq!(verdict.features.repeated_moves, 0);
+        assert!(!verdict.malicious, "{}", verdict.describe());
+
+        // Same straight line in longer steps is a script
+        let mut detector = PointerDetector::new(PointerConfig::default());
+        let mut verdict = None;
+        for i in 0..64u128 {
+            verdict = move_by(&mut detector, 10, 0, i * 8).or(verdict);
+        }
+        assert!(verdict.expect("window is full").malicious);
+    }
+}
@@ -0,0 +1,157 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::RwLock;
+use chrono::Local;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use crate::push_log;
+use super::storage::{invalid_data, write_atomically};
+use super::usb_auth::set_device_authorized;
+use super::whitelist::DeviceIdentity;
+
+lazy_static! {
+    /// Devices that were disabled by RustGuardian
+    pub static ref QUARANTINE: RwLock<Vec<QuarantineEntry>> = RwLock::new(Vec::new());
+}
+
+/// Record about a device that was blocked by one of the detectors
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QuarantineEntry {
+    pub port_path: String,
+    pub identity: Option<DeviceIdentity>,
+    pub name: String,
+    pub reason: String,
+    /// Detector that fired, e.g. keystroke-timing
+    pub detector: String,
+    pub blocked_at: String,
+}
+
+impl QuarantineEntry {
+    // Line that is printed by the blocked command
+    pub fn describe(&self) -> String {
+        let device = match &self.identity {
+            Some(identity) => identity.describe(),
+            None => format!("{} (port: {})", self.name, self.port_path),
+        };
+        format!("[{}] {} blocked by {}: {}", self.blocked_at, device, self.detector, self.reason)
+    }
+}
+
+// Quarantine is stored in the project root next to whitelist.json
+fn quarantine_file_path() -> String {
+    format!("{}/quarantine.json", env!("CARGO_MANIFEST_DIR"))
+}
+
+/// Loads records of blocked devices saved by a previous run
+pub fn load_quarantine() -> io::Result<usize> {
+    let data = match fs::read_to_string(quarantine_file_path()) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let entries: Vec<QuarantineEntry> = serde_json::from_str(&data).map_err(invalid_data)?;
+    let count = entries.len();
+    *QUARANTINE.write().unwrap() = entries;
+    Ok(count)
+}
+
+fn save_quarantine(entries: &[QuarantineEntry]) {
+    let result = serde_json::to_string_pretty(entries)
+        .map_err(invalid_data)
+        .and_then(|data| write_atomically(Path::new(&quarantine_file_path()), data.as_bytes(), 0o644));
+    if let Err(e) = result {
+        push_log(format!("[ERROR] Quarantine could not be saved: {}", e));
+    }
+}
+
+/// Disables the USB device (authorized=0) and records why it was done
+pub fn block_device(port_path: &str, name: &str, reason: &str, detector: &str) -> io::Result<()> {
+    let identity = udev::Device::from_subsystem_sysname("usb".to_string(), port_path.to_string())
+        .ok()
+        .and_then(|device| DeviceIdentity::from_udev(&device));
+    set_device_authorized(port_path, false)?;
+    push_log(format!("[ACTION] Power off for {} device (authorized=0)", port_path));
+
+    let entry = QuarantineEntry {
+        port_path: port_path.to_string(),
+        identity,
+        name: name.to_string(),
+        reason: reason.to_string(),
+        detector: detector.to_string(),
+        blocked_at: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+    };
+    let mut quarantine = QUARANTINE.write().unwrap();
+    quarantine.push(entry);
+    save_quarantine(&quarantine);
+    Ok(())
+}
+
+/// Re-enables the blocked device and removes it from the quarantine
+pub fn unblock(index: usize) -> Result<QuarantineEntry, String> {
+    let mut quarantine = QUARANTINE.write().unwrap();
+    if index >= quarantine.len() {
+        return Err(format!("No blocked device at index {}", index));
+    }
+    let entry = quarantine.remove(index);
+    save_quarantine(&quarantine);
+    // Device could be unplugged already, then only the record is removed
+    if let Err(e) = set_device_authorized(&entry.port_path, true) {
+        push_log(format!("[INFO] Device [{}] could not be authorized ({}), it was probably disconnected", entry.port_path, e));
+    } else {
+        push_log(format!("[ACTION] Device [{}] authorized (authorized=1)", entry.port_path));
+    }
+    Ok(entry)
+}
+
+/// Removes the record only, the device stays blocked until it is replugged
+pub fn forget(index: usize) -> Result<QuarantineEntry, String> {
+    let mut quarantine = QUARANTINE.write().unwrap();
+    if index >= quarantine.len() {
+        return Err(format!("No blocked device at index {}", index));
+    }
+    let entry = quarantine.remove(index);
+    save_quarantine(&quarantine);
+    Ok(entry)
+}
+
+/// True when the same device was quarantined before
+pub fn is_quarantined(device: &DeviceIdentity) -> bool {
+    QUARANTINE
+        .read()
+        .unwrap()
+        .iter()
+        .any(|entry| entry.identity.as_ref().is_some_and(|identity| identity.same_device(device)))
+}
+
+/// Blocks the device again if the same device was quarantined before and it was replugged.
+/// Returns true if the device was found in the quarantine.
+pub fn reblock_if_quarantined(device: &DeviceIdentity) -> bool {
+    let mut quarantine = QUARANTINE.write().unwrap();
+    let entry = match quarantine
+        .iter_mut()
+        .find(|entry| entry.identity.as_ref().is_some_and(|identity| identity.same_device(device)))
+    {
+        Some(entry) => entry,
+        None => return false,
+    };
+    entry.port_path = device.port_path.clone();
+    match set_device_authorized(&device.port_path, false) {
+        Ok(_) => push_log(format!("[ACTION] Quarantined device {} was plugged in again and blocked", device.describe())),
+        Err(e) => push_log(format!("[ERROR] Failed to block quarantined device [{}]: {}", device.port_path, e)),
+    }
+    save_quarantine(&quarantine);
+    true
+}
+
+// Logs all blocked devices with their index
+pub fn log_quarantine() {
+    push_log("[BLOCKED DEVICES] ---------------------".to_string());
+    let quarantine = QUARANTINE.read().unwrap();
+    if quarantine.is_empty() {
+        push_log("[BLOCKED DEVICES] list is empty".to_string());
+    }
+    for (i, entry) in quarantine.iter().enumerate() {
+        push_log(format!("{}: {}", i, entry.describe()));
+    }
+}
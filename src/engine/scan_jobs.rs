@@ -0,0 +1,346 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Local};
+use lazy_static::lazy_static;
+use notify_rust::Notification;
+use crate::{push_log, SIGNATURES};
+use super::filehash::{count_files, hash_all_files_in_dir, ScanSummary};
+use super::quarantine::is_quarantined;
+use super::usb_monitor::{subscribe, UsbEvent};
+use super::whitelist::{detect_new_media_mount, find_whitelist_entry};
+
+// Finished jobs that are kept for the scans command, the oldest are forgotten
+const MAX_FINISHED_JOBS: usize = 50;
+// Time the desktop needs to mount the volumes of a new flash drive
+const MOUNT_DELAY: Duration = Duration::from_secs(3);
+
+lazy_static! {
+    static ref SCAN_QUEUE: Mutex<ScanQueue> = Mutex::new(ScanQueue::default());
+}
+
+/// State of a scan job
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScanState {
+    Queued,
+    Running,
+    Done,
+    Cancelled,
+    Failed(String),
+}
+
+impl ScanState {
+    fn is_finished(&self) -> bool {
+        !matches!(self, ScanState::Queued | ScanState::Running)
+    }
+}
+
+impl fmt::Display for ScanState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScanState::Queued => write!(f, "queued"),
+            ScanState::Running => write!(f, "running"),
+            ScanState::Done => write!(f, "done"),
+            ScanState::Cancelled => write!(f, "cancelled"),
+            ScanState::Failed(reason) => write!(f, "failed ({})", reason),
+        }
+    }
+}
+
+/// Counters of a scan, shared with the hash workers
+#[derive(Default)]
+pub struct ScanProgress {
+    pub files_total: AtomicU64,
+    pub bytes_total: AtomicU64,
+    pub files_done: AtomicU64,
+    pub bytes_done: AtomicU64,
+    /// Files are counted before hashing, until then the totals are not known
+    counted: AtomicBool,
+    cancelled: AtomicBool,
+}
+
+impl ScanProgress {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    // Remaining time from the speed of hashing so far
+    fn eta(&self, hashing_for: Duration) -> Option<Duration> {
+        let done = self.bytes_done.load(Ordering::Relaxed);
+        let total = self.bytes_total.load(Ordering::Relaxed);
+        if done == 0 || hashing_for.is_zero() {
+            return None;
+        }
+        let rate = done as f64 / hashing_for.as_secs_f64();
+        Some(Duration::from_secs_f64(total.saturating_sub(done) as f64 / rate))
+    }
+
+    fn describe(&self, hashing_for: Option<Duration>) -> String {
+        if !self.counted.load(Ordering::Relaxed) {
+            return "counting files".to_string();
+        }
+        let bytes_total = self.bytes_total.load(Ordering::Relaxed);
+        let bytes_done = self.bytes_done.load(Ordering::Relaxed).min(bytes_total);
+        let percent = if bytes_total > 0 { bytes_done * 100 / bytes_total } else { 100 };
+        let eta = match hashing_for.and_then(|hashing_for| self.eta(hashing_for)) {
+            Some(eta) => format!(", ETA {}", format_duration(eta)),
+            None => String::new(),
+        };
+        format!(
+            "{}/{} files, {:.1}/{:.1} MB ({}%){}",
+            self.files_done.load(Ordering::Relaxed),
+            self.files_total.load(Ordering::Relaxed),
+            megabytes(bytes_done),
+            megabytes(bytes_total),
+            percent,
+            eta
+        )
+    }
+}
+
+fn megabytes(bytes: u64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 60 {
+        format!("{}m {}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Scan of the files of one mounted drive
+pub struct ScanJob {
+    pub id: u64,
+    pub path: PathBuf,
+    pub state: ScanState,
+    pub progress: Arc<ScanProgress>,
+    pub queued_at: DateTime<Local>,
+    /// When hashing started, after the files were counted
+    hashing_started: Option<Instant>,
+    pub summary: Option<ScanSummary>,
+}
+
+impl ScanJob {
+    pub fn describe(&self) -> String {
+        let details = match (&self.state, &self.summary) {
+            (ScanState::Running, _) => self.progress.describe(self.hashing_started.map(|started| started.elapsed())),
+            (_, Some(summary)) => summary.describe(),
+            _ => format!("queued at {}", self.queued_at.format("%H:%M:%S")),
+        };
+        format!("#{} {} {}: {}", self.id, self.path.display(), self.state, details)
+    }
+}
+
+#[derive(Default)]
+struct ScanQueue {
+    jobs: Vec<ScanJob>,
+    next_id: u64,
+    // Thread that runs the queued jobs one by one, each scan already uses all CPUs
+    runner_active: bool,
+}
+
+impl ScanQueue {
+    fn get_mut(&mut self, id: u64) -> Option<&mut ScanJob> {
+        self.jobs.iter_mut().find(|job| job.id == id)
+    }
+
+    fn forget_old_jobs(&mut self) {
+        let finished = self.jobs.iter().filter(|job| job.state.is_finished()).count();
+        let mut to_forget = finished.saturating_sub(MAX_FINISHED_JOBS);
+        self.jobs.retain(|job| {
+            if to_forget > 0 && job.state.is_finished() {
+                to_forget -= 1;
+                return false;
+            }
+            true
+        });
+    }
+}
+
+/// Queues a scan of the directory and returns its ID, the scan starts when the scans before it finish
+pub fn submit_scan(path: &Path) -> u64 {
+    let mut queue = SCAN_QUEUE.lock().unwrap();
+    queue.next_id += 1;
+    let id = queue.next_id;
+    queue.jobs.push(ScanJob {
+        id,
+        path: path.to_path_buf(),
+        state: ScanState::Queued,
+        progress: Arc::new(ScanProgress::default()),
+        queued_at: Local::now(),
+        hashing_started: None,
+        summary: None,
+    });
+    queue.forget_old_jobs();
+    push_log(format!("[INFO] Scan #{} of {} was queued, see scan status {}", id, path.display(), id));
+    if !queue.runner_active {
+        queue.runner_active = true;
+        thread::spawn(run_queue);
+    }
+    id
+}
+
+// Runs queued jobs until there are none
+fn run_queue() {
+    loop {
+        let (id, path, progress) = {
+            let mut queue = SCAN_QUEUE.lock().unwrap();
+            let job = match queue.jobs.iter_mut().find(|job| job.state == ScanState::Queued) {
+                Some(job) => job,
+                None => {
+                    queue.runner_active = false;
+                    return;
+                }
+            };
+            job.state = ScanState::Running;
+            (job.id, job.path.clone(), job.progress.clone())
+        };
+        let state = run_scan(id, &path, &progress);
+        let mut queue = SCAN_QUEUE.lock().unwrap();
+        if let Some(job) = queue.get_mut(id) {
+            match &job.summary {
+                Some(summary) => push_log(format!("[RESULT] Scan #{} of {} is {}: {}", id, path.display(), state, summary.describe())),
+                None => push_log(format!("[RESULT] Scan #{} of {} is {}", id, path.display(), state)),
+            }
+            job.state = state;
+        }
+    }
+}
+
+fn run_scan(id: u64, path: &Path, progress: &Arc<ScanProgress>) -> ScanState {
+    if !path.is_dir() {
+        return ScanState::Failed("not a directory".to_string());
+    }
+    // Only the pointer to the virus hashes is copied, not the hashes
+    let signatures = match SIGNATURES.lock().unwrap().clone() {
+        Some(signatures) if !signatures.is_empty() => signatures,
+        _ => {
+            push_log("There is no file to compare hashes, \
+                      please ensure that you have them or enable internet connection on the device so program could download them".to_string());
+            return ScanState::Failed("no virus hashes loaded".to_string());
+        }
+    };
+    let (files, bytes) = count_files(path, progress);
+    progress.files_total.store(files, Ordering::Relaxed);
+    progress.bytes_total.store(bytes, Ordering::Relaxed);
+    progress.counted.store(true, Ordering::Relaxed);
+    if let Some(job) = SCAN_QUEUE.lock().unwrap().get_mut(id) {
+        job.hashing_started = Some(Instant::now());
+    }
+    push_log(format!("[INFO] Scan #{} of {} started, {} files ({:.1} MB)", id, path.display(), files, megabytes(bytes)));
+    let summary = hash_all_files_in_dir(path, &signatures, progress);
+    if !summary.matches.is_empty() {
+        Notification::new()
+            .summary("Malicious files found")
+            .body(&format!("{} malicious files were found on {}", summary.matches.len(), path.display()))
+            .icon("dialog-warning")
+            .show()
+            .ok();
+    }
+    let state = if summary.cancelled { ScanState::Cancelled } else { ScanState::Done };
+    if let Some(job) = SCAN_QUEUE.lock().unwrap().get_mut(id) {
+        job.summary = Some(summary);
+    }
+    state
+}
+
+/// Cancels a queued or running scan, a running scan stops after the files that are being hashed
+pub fn cancel_scan(id: u64) -> Result<(), String> {
+    let mut queue = SCAN_QUEUE.lock().unwrap();
+    let job = queue.get_mut(id).ok_or_else(|| format!("No scan #{}", id))?;
+    match job.state {
+        ScanState::Queued => job.state = ScanState::Cancelled,
+        ScanState::Running => job.progress.cancel(),
+        _ => return Err(format!("Scan #{} is already {}", id, job.state)),
+    }
+    Ok(())
+}
+
+pub fn log_scans() {
+    push_log("[SCANS] ---------------------".to_string());
+    let queue = SCAN_QUEUE.lock().unwrap();
+    if queue.jobs.is_empty() {
+        push_log("[SCANS] list is empty".to_string());
+    }
+    for job in &queue.jobs {
+        push_log(job.describe());
+    }
+}
+
+/// Shows the state of the scan with the matched files
+pub fn log_scan_status(id: u64) -> Result<(), String> {
+    let queue = SCAN_QUEUE.lock().unwrap();
+    let job = queue.jobs.iter().find(|job| job.id == id).ok_or_else(|| format!("No scan #{}", id))?;
+    push_log(format!("[SCAN] {}", job.describe()));
+    if let Some(summary) = &job.summary {
+        for file in &summary.matches {
+            push_log(format!("    malicious: {} (flagged by {})", file.path, file.feeds.join(", ")));
+        }
+    }
+    Ok(())
+}
+
+/// Listens to the USB monitor in SafeConnection mode and scans the flash drive of every new device
+/// that is neither in the whitelist nor in quarantine
+pub struct MediaScanTrigger {
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MediaScanTrigger {
+    pub fn start() -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let usb_events = subscribe();
+        let thread_running = running.clone();
+        let handle = thread::spawn(move || {
+            while thread_running.load(Ordering::Relaxed) {
+                match usb_events.recv_timeout(Duration::from_millis(200)) {
+                    Ok(UsbEvent::Added(identity)) => {
+                        if find_whitelist_entry(&identity).is_none() && !is_quarantined(&identity) {
+                            thread::spawn(scan_new_media);
+                        }
+                    }
+                    Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+        MediaScanTrigger { running, handle: Some(handle) }
+    }
+
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            if let Err(e) = handle.join() {
+                push_log(format!("Thread error: {:?}", e));
+            }
+        }
+    }
+}
+
+impl Drop for MediaScanTrigger {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// Waits until the new device is mounted and queues the scan of its volume
+fn scan_new_media() {
+    thread::sleep(MOUNT_DELAY);
+    push_log("[INFO] Checking the device".to_string());
+    if let Some(mount_path) = detect_new_media_mount() {
+        push_log(format!("[USB MOUNT DETECTED] {:?}", mount_path));
+        submit_scan(Path::new(&mount_path));
+    }
+}
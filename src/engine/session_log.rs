@@ -0,0 +1,456 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use crate::push_log;
+use super::config::{config, resolve_path};
+use super::keymap::DecodedKey;
+use super::storage::write_atomically;
+use super::log_crypto::{is_encrypted_log, read_encrypted_log, read_encrypted_records, EncryptedLogWriter, LogKeySource};
+
+const HEADER_TITLE: &str = "RustGuardian keystroke session";
+// Line that separates the header from the entries
+const HEADER_END: &str = "---";
+const SESSION_EXTENSION: &str = "log";
+const ENCRYPTED_SESSION_EXTENSION: &str = "elog";
+// Keys of a keyboard without a malicious verdict kept in memory, older ones are forgotten
+const MAX_PENDING_KEYS: usize = 4096;
+// Numbered names tried when a session file with the same name already exists
+const MAX_NAME_ATTEMPTS: usize = 100;
+
+/// What is recorded about the keys of a monitored keyboard
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContentPolicy {
+    /// Every key is written as it is typed
+    Full,
+    /// Keys are kept in memory and written only when the keyboard is judged malicious
+    #[default]
+    MaliciousOnly,
+    /// Only the time of every key press, never the key itself
+    TimingOnly,
+}
+
+impl fmt::Display for ContentPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ContentPolicy::Full => "full",
+            ContentPolicy::MaliciousOnly => "malicious-only",
+            ContentPolicy::TimingOnly => "timing-only",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Metadata at the start of every session file, rewritten when the session is closed
+#[derive(Clone, Debug, Default)]
+pub struct SessionHeader {
+    pub device: String,
+    pub identity: String,
+    pub transport: String,
+    pub devnode: String,
+    pub syspath: String,
+    pub content: String,
+    pub started: String,
+    pub ended: String,
+    pub verdict: String,
+    /// Number of entries written when the session was closed, so a shortened log is noticed
+    pub entries: String,
+}
+
+impl SessionHeader {
+    fn fields(&self) -> [(&'static str, &String); 10] {
+        [
+            ("device", &self.device),
+            ("identity", &self.identity),
+            ("transport", &self.transport),
+            ("devnode", &self.devnode),
+            ("syspath", &self.syspath),
+            ("content", &self.content),
+            ("started", &self.started),
+            ("ended", &self.ended),
+            ("verdict", &self.verdict),
+            ("entries", &self.entries),
+        ]
+    }
+
+    fn render(&self) -> String {
+        let mut header = format!("{}\n", HEADER_TITLE);
+        for (name, value) in self.fields() {
+            // Values come from the device, a newline in them would break the header
+            header.push_str(&format!("{}: {}\n", name, value.replace('\n', " ")));
+        }
+        header.push_str(HEADER_END);
+        header.push('\n');
+        header
+    }
+
+    fn parse(text: &str) -> Option<SessionHeader> {
+        let mut lines = text.lines();
+        if lines.next()? != HEADER_TITLE {
+            return None;
+        }
+        let mut header = SessionHeader::default();
+        for line in lines {
+            if line == HEADER_END {
+                return Some(header);
+            }
+            let (name, value) = line.split_once(": ").unwrap_or((line.trim_end_matches(':'), ""));
+            let value = value.to_string();
+            match name {
+                "device" => header.device = value,
+                "identity" => header.identity = value,
+                "transport" => header.transport = value,
+                "devnode" => header.devnode = value,
+                "syspath" => header.syspath = value,
+                "content" => header.content = value,
+                "started" => header.started = value,
+                "ended" => header.ended = value,
+                "verdict" => header.verdict = value,
+                "entries" => header.entries = value,
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+fn timestamp() -> String {
+    Local::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string()
+}
+
+// Device names can contain anything, only safe characters are used in the file name
+fn file_name_part(text: &str) -> String {
+    let part: String = text
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .take(40)
+        .collect();
+    if part.is_empty() { "unknown".to_string() } else { part }
+}
+
+/// Directory of the session logs from the config
+pub fn sessions_dir() -> PathBuf {
+    resolve_path(&config().sessions.directory)
+}
+
+// Session file is written in plaintext only when it was chosen in the config
+enum SessionSink {
+    Plain(File),
+    Encrypted(EncryptedLogWriter),
+    // File could not be created or written, the events are dropped
+    Unavailable,
+}
+
+/// Log of one connection of one keyboard, every key and event is a timestamped entry
+pub struct SessionLog {
+    path: PathBuf,
+    sink: SessionSink,
+    header: SessionHeader,
+    entries: usize,
+    policy: ContentPolicy,
+    // Keys waiting for the verdict with the malicious-only policy, they never reach the disk unless it is malicious
+    pending_keys: VecDeque<String>,
+    malicious: bool,
+    closed: bool,
+}
+
+impl SessionLog {
+    /// Creates a new session file in the directory, the header is written right away
+    pub fn create(dir: &Path, mut header: SessionHeader) -> io::Result<Self> {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        let policy = config().sessions.content;
+        header.content = policy.to_string();
+        let now = Local::now();
+        header.started = now.format("%Y-%m-%d %H:%M:%S").to_string();
+        header.ended = "running".to_string();
+        if header.verdict.is_empty() {
+            header.verdict = "pending".to_string();
+        }
+        let devnode_name = Path::new(&header.devnode)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let key_source = LogKeySource::from_config();
+        let stem = format!(
+            "{}_{}_{}",
+            now.format("%Y%m%d-%H%M%S%3f"),
+            file_name_part(&header.device),
+            file_name_part(&devnode_name)
+        );
+        let extension = if key_source.is_some() { ENCRYPTED_SESSION_EXTENSION } else { SESSION_EXTENSION };
+        // A device re-enumerated within the same millisecond gets a numbered file instead of failing
+        let mut attempt = 1;
+        let (path, sink) = loop {
+            let path = if attempt == 1 {
+                dir.join(format!("{}.{}", stem, extension))
+            } else {
+                dir.join(format!("{}-{}.{}", stem, attempt, extension))
+            };
+            // Header is the first record of the encrypted log
+            let created = match &key_source {
+                Some(key_source) => EncryptedLogWriter::create(&path, key_source).and_then(|mut writer| {
+                    writer.append(&header.render())?;
+                    Ok(SessionSink::Encrypted(writer))
+                }),
+                None => OpenOptions::new().create_new(true).write(true).mode(0o600).open(&path).and_then(|mut file| {
+                    file.write_all(header.render().as_bytes())?;
+                    Ok(SessionSink::Plain(file))
+                }),
+            };
+            match created {
+                Ok(sink) => break (path, sink),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempt < MAX_NAME_ATTEMPTS => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        Ok(SessionLog {
+            path,
+            sink,
+            header,
+            entries: 0,
+            policy,
+            pending_keys: VecDeque::new(),
+            malicious: false,
+            closed: false,
+        })
+    }
+
+    /// Creates the session file like `create`, but a device is still monitored when it cannot be created,
+    /// only its events are not recorded
+    pub fn start(dir: &Path, header: SessionHeader) -> Self {
+        match SessionLog::create(dir, header.clone()) {
+            Ok(session) => session,
+            Err(e) => {
+                push_log(format!("[ERROR] Failed to create session log for {} in {}: {}, events of this connection will not be recorded", header.device, dir.display(), e));
+                SessionLog {
+                    path: dir.to_path_buf(),
+                    sink: SessionSink::Unavailable,
+                    header,
+                    entries: 0,
+                    policy: config().sessions.content,
+                    pending_keys: VecDeque::new(),
+                    malicious: false,
+                    closed: false,
+                }
+            }
+        }
+    }
+
+    // A failed write stops the recording, never the monitoring of the device
+    fn write_entry(&mut self, entry: String) {
+        let written = match &mut self.sink {
+            SessionSink::Plain(file) => writeln!(file, "{}", entry),
+            SessionSink::Encrypted(writer) => writer.append(&entry),
+            SessionSink::Unavailable => return,
+        };
+        match written {
+            Ok(()) => self.entries += 1,
+            Err(e) => {
+                push_log(format!("[ERROR] Failed to write session log {}: {}, further events will not be recorded", self.path.display(), e));
+                self.sink = SessionSink::Unavailable;
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// False when the session file could not be created or written
+    pub fn is_recorded(&self) -> bool {
+        !matches!(self.sink, SessionSink::Unavailable)
+    }
+
+    /// Adds the decoded key as the content policy allows
+    pub fn key(&mut self, key: &DecodedKey) {
+        if self.policy == ContentPolicy::TimingOnly {
+            return self.write_entry(format!("[{}] key *", timestamp()));
+        }
+        let text = match key {
+            DecodedKey::Char(' ') => "<SPACE>".to_string(),
+            DecodedKey::Char('\t') => "<TAB>".to_string(),
+            DecodedKey::Char(c) => c.to_string(),
+            DecodedKey::Enter => "<ENTER>".to_string(),
+            DecodedKey::Backspace => "<BACKSPACE>".to_string(),
+            DecodedKey::Token(token) => token.clone(),
+        };
+        let entry = format!("[{}] key {}", timestamp(), text);
+        if self.policy == ContentPolicy::MaliciousOnly && !self.malicious {
+            if self.pending_keys.len() >= MAX_PENDING_KEYS {
+                self.pending_keys.pop_front();
+            }
+            self.pending_keys.push_back(entry);
+            return;
+        }
+        self.write_entry(entry)
+    }
+
+    /// Adds an event of the session like a verdict or a response
+    pub fn event(&mut self, text: &str) {
+        self.write_entry(format!("[{}] event {}", timestamp(), text))
+    }
+
+    /// Verdict written into the header when the session is closed.
+    /// Keys held for the verdict are written when it is malicious and forgotten when it is not
+    pub fn set_verdict(&mut self, verdict: &str, malicious: bool) {
+        self.header.verdict = verdict.to_string();
+        if malicious && !self.malicious {
+            self.malicious = true;
+            while let Some(entry) = self.pending_keys.pop_front() {
+                self.write_entry(entry);
+            }
+        } else if !malicious {
+            self.purge_pending_keys();
+        }
+    }
+
+    fn purge_pending_keys(&mut self) {
+        if self.pending_keys.is_empty() {
+            return;
+        }
+        let purged = self.pending_keys.len();
+        self.pending_keys.clear();
+        self.event(&format!("{} keys were not recorded, the keyboard was not judged malicious", purged))
+    }
+
+    /// Writes the end time and the verdict into the header
+    pub fn close(&mut self) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.purge_pending_keys();
+        self.closed = true;
+        self.header.ended = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        self.header.entries = self.entries.to_string();
+        let file = match &mut self.sink {
+            SessionSink::Plain(file) => file,
+            SessionSink::Encrypted(writer) => return writer.rewrite_first(&self.header.render()),
+            SessionSink::Unavailable => return Ok(()),
+        };
+        file.sync_all()?;
+        let content = fs::read_to_string(&self.path)?;
+        let entries = match content.find(&format!("\n{}\n", HEADER_END)) {
+            Some(position) => &content[position + HEADER_END.len() + 2..],
+            None => content.as_str(),
+        };
+        let mut updated = self.header.render();
+        updated.push_str(entries);
+        write_atomically(&self.path, updated.as_bytes(), 0o600)
+    }
+}
+
+// Session is closed on every way out of the keylogger, errors included
+impl Drop for SessionLog {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            push_log(format!("[ERROR] Failed to close session log {}: {}", self.path.display(), e));
+        }
+    }
+}
+
+/// Session file with its header
+#[derive(Clone, Debug)]
+pub struct SessionSummary {
+    pub path: PathBuf,
+    pub header: SessionHeader,
+}
+
+impl SessionSummary {
+    pub fn describe(&self) -> String {
+        format!(
+            "{} {} ({}) ended {}, verdict {}",
+            self.header.started, self.header.device, self.header.transport, self.header.ended, self.header.verdict
+        )
+    }
+}
+
+/// All session logs in the directory, oldest first
+pub fn list_sessions(dir: &Path) -> io::Result<Vec<SessionSummary>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut sessions: Vec<SessionSummary> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == SESSION_EXTENSION || extension == ENCRYPTED_SESSION_EXTENSION)
+        })
+        .filter_map(|path| {
+            let header = if is_encrypted_log(&path) {
+                match read_encrypted_records(&path, &LogKeySource::configured(), 1) {
+                    Ok(records) => SessionHeader::parse(records.first()?)?,
+                    // Session is still listed, so it is clear that it exists
+                    Err(e) => SessionHeader {
+                        device: path.file_name()?.to_string_lossy().into_owned(),
+                        verdict: format!("unknown ({})", e),
+                        ..Default::default()
+                    },
+                }
+            } else {
+                SessionHeader::parse(&fs::read_to_string(&path).ok()?)?
+            };
+            Some(SessionSummary { path, header })
+        })
+        .collect();
+    // File names start with the start time
+    sessions.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(sessions)
+}
+
+/// Content of the session file, encrypted sessions are decrypted with the key from the source
+pub fn read_session(path: &Path, key_source: &LogKeySource) -> io::Result<String> {
+    if !is_encrypted_log(path) {
+        return fs::read_to_string(path);
+    }
+    let records = read_encrypted_log(path, key_source)?;
+    let mut content = String::new();
+    for (i, record) in records.iter().enumerate() {
+        content.push_str(record);
+        // Header record ends with a newline already
+        if i > 0 {
+            content.push('\n');
+        }
+    }
+    // Closed session knows how many entries it had, missing ones were cut off the end of the file
+    if let Some(header) = records.first().and_then(|record| SessionHeader::parse(record)) {
+        if let Ok(entries) = header.entries.parse::<usize>() {
+            if entries != records.len() - 1 {
+                content.push_str(&format!("WARNING: header lists {} entries but the log has {}\n", entries, records.len() - 1));
+            }
+        }
+    }
+    Ok(content)
+}
+
+pub fn log_sessions() {
+    push_log("[SESSIONS] ---------------------".to_string());
+    match list_sessions(&sessions_dir()) {
+        Ok(sessions) if sessions.is_empty() => push_log("[SESSIONS] list is empty".to_string()),
+        Ok(sessions) => {
+            for (i, session) in sessions.iter().enumerate() {
+                push_log(format!("{}: {}", i, session.describe()));
+            }
+        }
+        Err(e) => push_log(format!("[ERROR] Failed to read sessions from {}: {}", sessions_dir().display(), e)),
+    }
+}
+
+/// Shows the session with the index from the list in the TUI log
+pub fn show_session(index: usize) -> Result<(), String> {
+    let sessions = list_sessions(&sessions_dir()).map_err(|e| format!("Failed to read sessions: {}", e))?;
+    let session = sessions.get(index).ok_or_else(|| format!("No session at index {}", index))?;
+    let content = read_session(&session.path, &LogKeySource::configured()).map_err(|e| format!("Failed to read {}: {}", session.path.display(), e))?;
+    push_log(format!("[SESSION] {}", session.path.display()));
+    for line in content.lines() {
+        push_log(line.to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Numbers the temporary files of this process, so concurrent writers never share one
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Creates a new file next to the target with a name no other writer uses, it is moved into place afterwards
+pub fn create_temp_file(path: &Path, mode: u32) -> io::Result<(PathBuf, fs::File)> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(format!(".{}.{}.tmp", std::process::id(), TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let tmp_path = PathBuf::from(tmp_name);
+    let file = fs::OpenOptions::new().create_new(true).write(true).mode(mode).open(&tmp_path)?;
+    Ok((tmp_path, file))
+}
+
+/// Writes the data into a temporary file next to the target and then renames it over the target,
+/// so a crash never leaves a half written file. Mode is 0o600 for files only root may read (keystroke logs)
+pub fn write_atomically(path: &Path, data: &[u8], mode: u32) -> io::Result<()> {
+    let (tmp_path, mut tmp_file) = create_temp_file(path, mode)?;
+    let written = tmp_file
+        .write_all(data)
+        .and_then(|_| tmp_file.sync_all())
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    written?;
+    // Rename is only durable once the directory is synced too
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::File::open(directory)?.sync_all()
+}
+
+pub fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
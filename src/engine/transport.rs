@@ -0,0 +1,129 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use super::virtual_input::{find_uinput_owners, is_virtual_input, UinputOwner};
+
+/// Driver bound to the HID or serio device of a keyboard, unbinding it disconnects the keyboard
+#[derive(Clone, Debug)]
+pub struct DriverBinding {
+    pub subsystem: String,
+    pub driver: String,
+    pub sysname: String,
+}
+
+impl DriverBinding {
+    // Nearest parent of the input device that is a HID or serio device with a driver
+    fn from_parents(device: &udev::Device) -> Option<Self> {
+        let mut current = device.parent();
+        while let Some(parent) = current {
+            let subsystem = parent.subsystem().and_then(|subsystem| subsystem.to_str()).unwrap_or_default().to_string();
+            if subsystem == "hid" || subsystem == "serio" {
+                if let Some(driver) = parent.driver().and_then(|driver| driver.to_str()) {
+                    return Some(DriverBinding {
+                        subsystem,
+                        driver: driver.to_string(),
+                        sysname: parent.sysname().to_string_lossy().into_owned(),
+                    });
+                }
+            }
+            current = parent.parent();
+        }
+        None
+    }
+
+    /// Detaches the driver, the keyboard stops sending input until it is connected again
+    pub fn unbind(&self) -> io::Result<()> {
+        fs::write(format!("/sys/bus/{}/drivers/{}/unbind", self.subsystem, self.driver), &self.sysname)
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{} driver {} of {}", self.subsystem, self.driver, self.sysname)
+    }
+}
+
+/// How the monitored input device is connected, decides how it is disabled after an attack
+#[derive(Clone, Debug)]
+pub enum InputTransport {
+    /// USB device, the port path is the sysname of its usb_device parent
+    Usb { port_path: String },
+    Bluetooth { binding: Option<DriverBinding> },
+    I2c { binding: Option<DriverBinding> },
+    /// Serial and PS/2 keyboards connected through serio
+    Serial { binding: Option<DriverBinding> },
+    /// Device created through uinput with the processes that could have created it
+    Virtual { owners: Vec<UinputOwner> },
+    Unknown,
+}
+
+impl InputTransport {
+    /// Finds the transport of the input device from its udev parents.
+    /// The nearest parent decides, a Bluetooth keyboard has the USB Bluetooth adapter as a parent too
+    pub fn from_udev(device: &udev::Device) -> Self {
+        if is_virtual_input(device) {
+            return InputTransport::Virtual { owners: find_uinput_owners() };
+        }
+        let mut current = device.parent();
+        while let Some(parent) = current {
+            let subsystem = parent.subsystem().and_then(|subsystem| subsystem.to_str()).unwrap_or_default().to_string();
+            let devtype = parent.devtype().and_then(|devtype| devtype.to_str()).unwrap_or_default().to_string();
+            match (subsystem.as_str(), devtype.as_str()) {
+                ("usb", "usb_device") => {
+                    return InputTransport::Usb { port_path: parent.sysname().to_string_lossy().into_owned() };
+                }
+                ("bluetooth", _) => return InputTransport::Bluetooth { binding: DriverBinding::from_parents(device) },
+                ("i2c", _) => return InputTransport::I2c { binding: DriverBinding::from_parents(device) },
+                ("serio", _) | ("tty", _) => return InputTransport::Serial { binding: DriverBinding::from_parents(device) },
+                _ => {}
+            }
+            current = parent.parent();
+        }
+        InputTransport::Unknown
+    }
+
+    /// Laptop keyboards, touchpads and buttons are connected through serio or i2c.
+    /// A device without a known transport is not trusted, it is analysed like a new one
+    pub fn is_built_in(&self) -> bool {
+        matches!(self, InputTransport::Serial { .. } | InputTransport::I2c { .. })
+    }
+
+    /// Driver that is unbound to disconnect the keyboard, USB and virtual keyboards are disabled differently
+    pub fn binding(&self) -> Option<&DriverBinding> {
+        match self {
+            InputTransport::Bluetooth { binding } | InputTransport::I2c { binding } | InputTransport::Serial { binding } => {
+                binding.as_ref()
+            }
+            _ => None,
+        }
+    }
+
+    /// Short description for logs
+    pub fn describe(&self) -> String {
+        match self {
+            InputTransport::Usb { port_path } => format!("usb port {}", port_path),
+            InputTransport::Virtual { owners } if owners.is_empty() => "virtual, no process with /dev/uinput found".to_string(),
+            InputTransport::Virtual { owners } => format!(
+                "virtual, created by {}",
+                owners.iter().map(|owner| owner.describe()).collect::<Vec<_>>().join(" or ")
+            ),
+            InputTransport::Unknown => "unknown".to_string(),
+            _ => match self.binding() {
+                Some(binding) => format!("{}, {}", self, binding.describe()),
+                None => format!("{}, no driver to unbind", self),
+            },
+        }
+    }
+}
+
+impl fmt::Display for InputTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            InputTransport::Usb { .. } => "usb",
+            InputTransport::Bluetooth { .. } => "bluetooth",
+            InputTransport::I2c { .. } => "i2c",
+            InputTransport::Serial { .. } => "serial",
+            InputTransport::Virtual { .. } => "virtual",
+            InputTransport::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use super::whitelist::{read_interfaces, UsbInterface};
+
+// All USB devices and root hubs are visible in this directory
+const USB_DEVICES_PATH: &str = "/sys/bus/usb/devices";
+
+// Writes a value into the sysfs attribute of the USB device
+fn write_attribute(sysname: &str, attribute: &str, value: &str) -> io::Result<()> {
+    let attribute_file = format!("{}/{}/{}", USB_DEVICES_PATH, sysname, attribute);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(&attribute_file)?;
+    file.write_all(value.as_bytes())
+}
+
+/// Sets `authorized_default` and `interface_authorized_default` on every root hub (usb1, usb2...).
+/// With 0 new devices are attached, but can not communicate with the OS until they are authorized,
+/// and interfaces of authorized devices have to be authorized one by one.
+/// Returns the number of root hubs that were changed.
+pub fn set_default_authorization(authorize: bool) -> io::Result<usize> {
+    let value = if authorize { "1" } else { "0" };
+    let mut changed = 0;
+    for entry in fs::read_dir(USB_DEVICES_PATH)?.flatten() {
+        let sysname = entry.file_name().to_string_lossy().into_owned();
+        if sysname.starts_with("usb") {
+            write_attribute(&sysname, "authorized_default", value)?;
+            // Older kernels do not have interface authorization, then only whole devices are blocked
+            match write_attribute(&sysname, "interface_authorized_default", value) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            changed += 1;
+        }
+    }
+    Ok(changed)
+}
+
+/// DefaultDeny mode of the root hubs. The default is restored when it is dropped, so no way out of
+/// RustGuardian (Esc, an error or a panic) leaves the machine where no new USB device works
+#[derive(Default)]
+pub struct DefaultDeny {
+    enabled: bool,
+}
+
+impl DefaultDeny {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns the number of root hubs that were changed
+    pub fn enable(&mut self) -> io::Result<usize> {
+        // Hubs changed before an error are restored right away
+        self.enabled = true;
+        match set_default_authorization(false) {
+            Ok(hubs) => Ok(hubs),
+            Err(e) => {
+                let _ = self.disable();
+                Err(e)
+            }
+        }
+    }
+
+    pub fn disable(&mut self) -> io::Result<()> {
+        if self.enabled {
+            set_default_authorization(true)?;
+            self.enabled = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DefaultDeny {
+    fn drop(&mut self) {
+        // TUI is not drawn anymore, the error goes to the terminal
+        if let Err(e) = self.disable() {
+            eprintln!("Failed to restore USB authorization: {}", e);
+        }
+    }
+}
+
+/// Datapath example: /sys/bus/usb/devices/2-1/authorized.
+/// When the device is not authorized it could not communicate with OS.
+pub fn set_device_authorized(port_path: &str, authorize: bool) -> io::Result<()> {
+    write_attribute(port_path, "authorized", if authorize { "1" } else { "0" })
+}
+
+/// Check if the device is allowed to communicate with OS
+pub fn is_device_authorized(port_path: &str) -> bool {
+    let authorized_file = Path::new(USB_DEVICES_PATH).join(port_path).join("authorized");
+    fs::read_to_string(authorized_file)
+        .map(|value| value.trim() == "1")
+        .unwrap_or(false)
+}
+
+/// Interfaces of the connected device taken from its descriptors
+pub fn list_device_interfaces(port_path: &str) -> Vec<UsbInterface> {
+    read_interfaces(&Path::new(USB_DEVICES_PATH).join(port_path))
+}
+
+// Interface directory is named <port>:<configuration>.<interface>, e.g. 2-1.3:1.0
+fn interface_sysname(port_path: &str, interface_number: u8) -> io::Result<String> {
+    let configuration_file = Path::new(USB_DEVICES_PATH).join(port_path).join("bConfigurationValue");
+    let configuration = fs::read_to_string(configuration_file)?;
+    let configuration = configuration.trim();
+    if configuration.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "device is not configured"));
+    }
+    Ok(format!("{}:{}.{}", port_path, configuration, interface_number))
+}
+
+/// Allows or blocks a single interface of the device, so for example mass storage
+/// of a composite device could work while its keyboard interface stays blocked.
+/// The device itself has to be authorized, otherwise its interfaces do not exist.
+pub fn set_interface_authorized(port_path: &str, interface_number: u8, authorize: bool) -> io::Result<()> {
+    let sysname = interface_sysname(port_path, interface_number)?;
+    write_attribute(&sysname, "authorized", if authorize { "1" } else { "0" })
+}
+
+/// Check if the interface of the device is allowed to communicate with OS
+pub fn is_interface_authorized(port_path: &str, interface_number: u8) -> bool {
+    match interface_sysname(port_path, interface_number) {
+        Ok(sysname) => fs::read_to_string(Path::new(USB_DEVICES_PATH).join(sysname).join("authorized"))
+            .map(|value| value.trim() == "1")
+            .unwrap_or(false),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,89 @@
+use udev::{EventType, MonitorBuilder};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::os::unix::io::AsRawFd;
+use std::os::fd::BorrowedFd;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use lazy_static::lazy_static;
+use crate::push_log;
+use super::whitelist::DeviceIdentity;
+
+/// Typed USB event that is sent to every subscriber
+#[derive(Clone, Debug)]
+pub enum UsbEvent {
+    Added(DeviceIdentity),
+    /// Attributes are not readable anymore on remove, only the port path is known
+    Removed { port_path: String },
+    Changed(DeviceIdentity),
+}
+
+lazy_static! {
+    static ref SUBSCRIBERS: Mutex<Vec<Sender<UsbEvent>>> = Mutex::new(Vec::new());
+}
+
+/// Returns a channel that receives all USB events from now on
+pub fn subscribe() -> Receiver<UsbEvent> {
+    let (tx, rx) = mpsc::channel();
+    SUBSCRIBERS.lock().unwrap().push(tx);
+    rx
+}
+
+// Sends the event to all subscribers, the ones that were dropped are removed
+fn broadcast(event: UsbEvent) {
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+}
+
+/// Starts the thread that listens to udev for USB devices (not interfaces) being added, removed or changed
+pub fn start_usb_monitor(running: Arc<AtomicBool>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        if let Err(e) = monitor_usb_devices(running) {
+            push_log(format!("[ERROR] USB monitor stopped: {}", e));
+        }
+    })
+}
+
+fn monitor_usb_devices(running: Arc<AtomicBool>) -> std::io::Result<()> {
+    let socket = MonitorBuilder::new()?
+        .match_subsystem_devtype("usb", "usb_device")?
+        .listen()?;
+    let socket_fd = socket.as_raw_fd();
+    let mut poll_fds = [PollFd::new(unsafe { BorrowedFd::borrow_raw(socket_fd) }, PollFlags::POLLIN)];
+
+    while running.load(Ordering::Relaxed) {
+        match poll(&mut poll_fds, 100u16) {
+            Ok(0) => continue,
+            Ok(_) => {
+                for event in socket.iter() {
+                    let device = event.device();
+                    let port_path = device.sysname().to_string_lossy().into_owned();
+                    // Root hubs are part of the machine
+                    if port_path.starts_with("usb") {
+                        continue;
+                    }
+                    let usb_event = match event.event_type() {
+                        EventType::Add => DeviceIdentity::from_udev(&device).map(UsbEvent::Added),
+                        EventType::Change => DeviceIdentity::from_udev(&device).map(UsbEvent::Changed),
+                        EventType::Remove => Some(UsbEvent::Removed { port_path }),
+                        _ => None,
+                    };
+                    if let Some(usb_event) = usb_event {
+                        broadcast(usb_event);
+                    }
+                }
+            }
+            // Signal interrupted the wait, nothing is wrong with the socket
+            Err(Errno::EINTR) => continue,
+            Err(e) => {
+                push_log(format!("[ERROR] USB monitor poll failed: {}, USB devices are not watched anymore", e));
+                break;
+            }
+        }
+    }
+    Ok(())
+}
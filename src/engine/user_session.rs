@@ -0,0 +1,102 @@
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+// loginctl is asked again only after this time, keys come much faster
+const REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// State of the graphical or console sessions of the users, from systemd-logind
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UserActivity {
+    /// Someone is working in an active session
+    Active,
+    /// Active session did not get any input for a while
+    Idle,
+    /// Active session is locked
+    Locked,
+    /// logind is not available or there is no session with a seat
+    #[default]
+    Unknown,
+}
+
+impl UserActivity {
+    /// Nobody should be typing, keys arriving now are suspicious
+    pub fn is_inactive(&self) -> bool {
+        matches!(self, UserActivity::Idle | UserActivity::Locked)
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            UserActivity::Active => "active",
+            UserActivity::Idle => "idle",
+            UserActivity::Locked => "locked",
+            UserActivity::Unknown => "unknown",
+        }
+    }
+}
+
+fn loginctl(args: &[&str]) -> Option<String> {
+    let output = Command::new("loginctl").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Property of "loginctl show-session", properties are printed as Name=value
+fn property<'a>(properties: &'a str, name: &str) -> Option<&'a str> {
+    properties
+        .lines()
+        .find_map(|line| line.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')))
+}
+
+/// Asks logind about the active sessions with a seat. RustGuardian runs as root without a session,
+/// so all sessions are listed and the active ones decide
+pub fn user_activity() -> UserActivity {
+    let sessions = match loginctl(&["list-sessions", "--no-legend"]) {
+        Some(sessions) => sessions,
+        None => return UserActivity::Unknown,
+    };
+    let mut activity = UserActivity::Unknown;
+    for id in sessions.lines().filter_map(|line| line.split_whitespace().next()) {
+        let properties = match loginctl(&["show-session", id, "-p", "Active", "-p", "Seat", "-p", "LockedHint", "-p", "IdleHint"]) {
+            Some(properties) => properties,
+            None => continue,
+        };
+        // Remote sessions have no seat, keys of a local keyboard do not go to them
+        if property(&properties, "Active") != Some("yes") || property(&properties, "Seat").unwrap_or_default().is_empty() {
+            continue;
+        }
+        if property(&properties, "LockedHint") == Some("yes") {
+            activity = UserActivity::Locked;
+        } else if property(&properties, "IdleHint") == Some("yes") {
+            if activity != UserActivity::Locked {
+                activity = UserActivity::Idle;
+            }
+        } else {
+            // One session where someone works is enough
+            return UserActivity::Active;
+        }
+    }
+    activity
+}
+
+/// Remembers the last answer of logind, so it is not asked on every key
+#[derive(Default)]
+pub struct ActivityProbe {
+    checked: Option<Instant>,
+    activity: UserActivity,
+}
+
+impl ActivityProbe {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn current(&mut self) -> UserActivity {
+        if self.checked.is_none_or(|checked| checked.elapsed() >= REFRESH_INTERVAL) {
+            self.activity = user_activity();
+            self.checked = Some(Instant::now());
+        }
+        self.activity
+    }
+}
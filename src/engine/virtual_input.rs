@@ -0,0 +1,83 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Input devices created through uinput live under this sysfs path
+const VIRTUAL_INPUT_DEVPATH: &str = "/devices/virtual/input";
+const UINPUT_NODES: [&str; 2] = ["/dev/uinput", "/dev/input/uinput"];
+
+/// Process that has /dev/uinput open and could have created a virtual keyboard
+#[derive(Clone, Debug)]
+pub struct UinputOwner {
+    pub pid: i32,
+    pub name: String,
+    pub cmdline: String,
+}
+
+impl UinputOwner {
+    pub fn describe(&self) -> String {
+        if self.cmdline.is_empty() {
+            format!("{} (pid {})", self.name, self.pid)
+        } else {
+            format!("{} (pid {}): {}", self.name, self.pid, self.cmdline)
+        }
+    }
+
+    /// True while the process is running and still has /dev/uinput open, so the pid was not reused
+    pub fn still_owns_uinput(&self) -> bool {
+        holds_uinput(self.pid)
+    }
+}
+
+/// Input device without any hardware parent, created by a process through uinput
+pub fn is_virtual_input(device: &udev::Device) -> bool {
+    device.devpath().to_string_lossy().starts_with(VIRTUAL_INPUT_DEVPATH)
+}
+
+// True when one of the file descriptors of the process points to uinput
+fn holds_uinput(pid: i32) -> bool {
+    let entries = match fs::read_dir(format!("/proc/{}/fd", pid)) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.filter_map(|entry| entry.ok()).any(|entry| {
+        fs::read_link(entry.path())
+            .map(|target| UINPUT_NODES.iter().any(|node| target == Path::new(node)))
+            .unwrap_or(false)
+    })
+}
+
+/// Processes that have /dev/uinput open, RustGuardian itself is left out since it replays held back input.
+/// Processes of other users can be seen only when running as root
+pub fn find_uinput_owners() -> Vec<UinputOwner> {
+    let own_pid = std::process::id() as i32;
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut owners: Vec<UinputOwner> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|pid| *pid != own_pid && holds_uinput(*pid))
+        .map(|pid| UinputOwner {
+            pid,
+            name: fs::read_to_string(format!("/proc/{}/comm", pid))
+                .map(|name| name.trim().to_string())
+                .unwrap_or_else(|_| "unknown".to_string()),
+            cmdline: fs::read(format!("/proc/{}/cmdline", pid))
+                .map(|cmdline| String::from_utf8_lossy(&cmdline).replace('\0', " ").trim().to_string())
+                .unwrap_or_default(),
+        })
+        .collect();
+    owners.sort_by_key(|owner| owner.pid);
+    owners
+}
+
+/// Kills the process that injects the keystrokes
+pub fn kill_process(pid: i32) -> io::Result<()> {
+    if unsafe { libc::kill(pid, libc::SIGKILL) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
@@ -1,40 +1,323 @@
-use std::process::Command;
-use lazy_static::lazy_static;
-use crate::RwLock;
+use crate::WHITELIST;
 use crate::WHITELIST_PATHS;
-/// Tracks which device IDs we've already shown notifications for
-use std::collections::HashMap;
+use std::collections::BTreeSet;
 use crate::Path;
+use std::fmt;
 use std::fs;
+use std::io;
+use serde::{Deserialize, Serialize};
+use super::storage::{invalid_data, write_atomically};
 
-lazy_static! {
-    pub static ref WHITELIST: RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
-}
-
-/// Scans currently connected USB devices using `lsusb`,
-/// extracts their ID (e.g. 046d:c534), shows notification if not yet shown,
-/// and returns a set of all connected device IDs.
-pub fn create_whitelist_from_connected_devices() -> HashMap<String, String> {
-    let mut devices = HashMap::new();
-
-    if let Ok(output) = Command::new("lsusb").output() {
-        if let Ok(stdout) = String::from_utf8(output.stdout) {
-            for line in stdout.lines() {
-                if let Some(id_part) = line.split("ID").nth(1) {
-                    let mut parts = id_part.trim().split_whitespace();
-                    let id = parts.next().unwrap_or("unknown").to_string();
-                    let name = parts.collect::<Vec<_>>().join(" ");
-                    if !id.is_empty() {
-                        devices.insert(id, name);
-                    }
+/// Version of the on-disk whitelist format, bumped whenever the layout changes
+pub const WHITELIST_FILE_VERSION: u32 = 2;
+
+/// Everything we know about a USB device that can be used to recognize it again
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceIdentity {
+    pub vendor_id: String,
+    pub product_id: String,
+    pub serial: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    /// Physical port path of the device, e.g. 2-1.3
+    pub port_path: String,
+    /// Classes of all interfaces the device exposes (3 = HID, 8 = mass storage...)
+    pub interface_classes: BTreeSet<u8>,
+}
+
+impl DeviceIdentity {
+    /// Builds the identity from the udev device of type `usb_device`
+    pub fn from_udev(device: &udev::Device) -> Option<Self> {
+        let attribute = |name: &str| {
+            device
+                .attribute_value(name)
+                .map(|value| value.to_string_lossy().trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        Some(DeviceIdentity {
+            vendor_id: attribute("idVendor")?,
+            product_id: attribute("idProduct")?,
+            serial: attribute("serial"),
+            manufacturer: attribute("manufacturer"),
+            product: attribute("product"),
+            port_path: device.sysname().to_string_lossy().into_owned(),
+            interface_classes: read_interface_classes(device.syspath()),
+        })
+    }
+
+    // VID:PID string the same as lsusb shows
+    pub fn id(&self) -> String {
+        format!("{}:{}", self.vendor_id, self.product_id)
+    }
+
+    // Human readable name of the device
+    pub fn name(&self) -> String {
+        match (&self.manufacturer, &self.product) {
+            (Some(manufacturer), Some(product)) => format!("{} {}", manufacturer, product),
+            (None, Some(product)) => product.clone(),
+            (Some(manufacturer), None) => manufacturer.clone(),
+            (None, None) => "Unknown device".to_string(),
+        }
+    }
+
+    /// Same device regardless of the port it is plugged into
+    pub fn same_device(&self, other: &DeviceIdentity) -> bool {
+        self.vendor_id == other.vendor_id
+            && self.product_id == other.product_id
+            && same_descriptors(self, other)
+    }
+
+    // Full description that is printed into logs
+    pub fn describe(&self) -> String {
+        let classes = self
+            .interface_classes
+            .iter()
+            .map(|class| format!("{:02x}", class))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{} {} (serial: {}, port: {}, interfaces: {})",
+            self.id(),
+            self.name(),
+            self.serial.as_deref().unwrap_or("none"),
+            self.port_path,
+            classes
+        )
+    }
+}
+
+/// How closely a connected device has to match a whitelist entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MatchPolicy {
+    /// Every field including the port the device was approved on
+    Strict,
+    /// Every field except the port, so the device may be moved to another port
+    IgnorePort,
+    /// Only VID:PID, the weakest policy that was used before
+    IdOnly,
+}
+
+impl MatchPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "strict" => Some(MatchPolicy::Strict),
+            "ignore-port" => Some(MatchPolicy::IgnorePort),
+            "id-only" => Some(MatchPolicy::IdOnly),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MatchPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MatchPolicy::Strict => "strict",
+            MatchPolicy::IgnorePort => "ignore-port",
+            MatchPolicy::IdOnly => "id-only",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Single trusted device together with the policy used to recognize it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WhitelistEntry {
+    pub identity: DeviceIdentity,
+    pub policy: MatchPolicy,
+    /// Numbers of interfaces that were approved, `None` means the whole device
+    #[serde(default)]
+    pub approved_interfaces: Option<BTreeSet<u8>>,
+}
+
+impl WhitelistEntry {
+    pub fn matches(&self, device: &DeviceIdentity) -> bool {
+        let trusted = &self.identity;
+        if trusted.vendor_id != device.vendor_id || trusted.product_id != device.product_id {
+            return false;
+        }
+        match self.policy {
+            MatchPolicy::IdOnly => true,
+            MatchPolicy::IgnorePort => same_descriptors(trusted, device),
+            MatchPolicy::Strict => same_descriptors(trusted, device) && trusted.port_path == device.port_path,
+        }
+    }
+}
+
+// Compares serial, strings and interfaces, so a clone of VID:PID with another interface set does not pass
+fn same_descriptors(a: &DeviceIdentity, b: &DeviceIdentity) -> bool {
+    a.serial == b.serial
+        && a.manufacturer == b.manufacturer
+        && a.product == b.product
+        && a.interface_classes == b.interface_classes
+}
+
+/// Check if the device matches any entry of the whitelist
+pub fn is_whitelisted(device: &DeviceIdentity) -> bool {
+    WHITELIST.read().unwrap().iter().any(|entry| entry.matches(device))
+}
+
+/// Returns the first whitelist entry that matches the device
+pub fn find_whitelist_entry(device: &DeviceIdentity) -> Option<WhitelistEntry> {
+    WHITELIST.read().unwrap().iter().find(|entry| entry.matches(device)).cloned()
+}
+
+/// Single interface of a USB device as described in its interface descriptor
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UsbInterface {
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+impl UsbInterface {
+    // Name of the interface class for the review list
+    pub fn class_name(&self) -> &'static str {
+        match self.class {
+            0x01 => "Audio",
+            0x02 => "Communications",
+            0x03 => "HID",
+            0x06 => "Image",
+            0x07 => "Printer",
+            0x08 => "Mass storage",
+            0x09 => "Hub",
+            0x0a => "CDC data",
+            0x0b => "Smart card",
+            0x0e => "Video",
+            0xe0 => "Wireless",
+            0xef => "Miscellaneous",
+            0xfe => "Application specific",
+            0xff => "Vendor specific",
+            _ => "Unknown",
+        }
+    }
+}
+
+/// Reads interfaces of the device from the raw descriptors, since they are readable
+/// even when the device is not authorized and its interfaces were not created yet
+pub fn read_interfaces(syspath: &Path) -> Vec<UsbInterface> {
+    let mut interfaces: Vec<UsbInterface> = Vec::new();
+    if let Ok(descriptors) = fs::read(syspath.join("descriptors")) {
+        let mut offset = 0;
+        while offset + 1 < descriptors.len() {
+            let length = descriptors[offset] as usize;
+            if length == 0 {
+                break;
+            }
+            // Interface descriptor has type 4, alternate settings of the same interface are skipped
+            if descriptors[offset + 1] == 4 && length >= 9 && offset + 8 <= descriptors.len() {
+                let number = descriptors[offset + 2];
+                if !interfaces.iter().any(|interface| interface.number == number) {
+                    interfaces.push(UsbInterface {
+                        number,
+                        class: descriptors[offset + 5],
+                        subclass: descriptors[offset + 6],
+                        protocol: descriptors[offset + 7],
+                    });
                 }
             }
+            offset += length;
         }
     }
+    interfaces
+}
+
+fn read_interface_classes(syspath: &Path) -> BTreeSet<u8> {
+    read_interfaces(syspath).iter().map(|interface| interface.class).collect()
+}
 
+/// Enumerates currently connected USB devices through udev and returns their identities.
+/// Root hubs are skipped since they are part of the machine itself.
+pub fn list_connected_devices() -> Vec<DeviceIdentity> {
+    let mut devices = Vec::new();
+    let mut enumerator = match udev::Enumerator::new() {
+        Ok(enumerator) => enumerator,
+        Err(_) => return devices,
+    };
+    if enumerator.match_subsystem("usb").is_err() || enumerator.match_property("DEVTYPE", "usb_device").is_err() {
+        return devices;
+    }
+    if let Ok(list) = enumerator.scan_devices() {
+        for device in list {
+            if device.sysname().to_string_lossy().starts_with("usb") {
+                continue;
+            }
+            if let Some(identity) = DeviceIdentity::from_udev(&device) {
+                devices.push(identity);
+            }
+        }
+    }
     devices
 }
 
+// Layout of the whitelist file that is kept between restarts
+#[derive(Serialize, Deserialize)]
+struct WhitelistFile {
+    version: u32,
+    devices: Vec<WhitelistEntry>,
+}
+
+// Entries of the first version of the file only had VID:PID and the lsusb name
+#[derive(Deserialize)]
+struct WhitelistFileV1 {
+    devices: Vec<WhitelistFileEntryV1>,
+}
+
+#[derive(Deserialize)]
+struct WhitelistFileEntryV1 {
+    id: String,
+    name: String,
+}
+
+/// Loads the whitelist saved by a previous run.
+/// Returns `Ok(None)` when there is no whitelist file yet (first run).
+pub fn load_whitelist(path: &Path) -> io::Result<Option<Vec<WhitelistEntry>>> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let value: serde_json::Value = serde_json::from_str(&data).map_err(invalid_data)?;
+    match value.get("version").and_then(|v| v.as_u64()) {
+        Some(1) => {
+            // Old entries can only be matched by VID:PID
+            let file: WhitelistFileV1 = serde_json::from_value(value).map_err(invalid_data)?;
+            let entries = file
+                .devices
+                .into_iter()
+                .map(|device| {
+                    let mut ids = device.id.splitn(2, ':');
+                    WhitelistEntry {
+                        identity: DeviceIdentity {
+                            vendor_id: ids.next().unwrap_or_default().to_string(),
+                            product_id: ids.next().unwrap_or_default().to_string(),
+                            product: Some(device.name),
+                            ..Default::default()
+                        },
+                        policy: MatchPolicy::IdOnly,
+                        approved_interfaces: None,
+                    }
+                })
+                .collect();
+            Ok(Some(entries))
+        }
+        Some(version) if version == WHITELIST_FILE_VERSION as u64 => {
+            let file: WhitelistFile = serde_json::from_value(value).map_err(invalid_data)?;
+            Ok(Some(file.devices))
+        }
+        version => Err(invalid_data(format!("unsupported whitelist version {:?}", version))),
+    }
+}
+
+/// Writes the whitelist to disk, the old file is replaced atomically
+pub fn save_whitelist(path: &Path, whitelist: &[WhitelistEntry]) -> io::Result<()> {
+    let file = WhitelistFile { version: WHITELIST_FILE_VERSION, devices: whitelist.to_vec() };
+    let data = serde_json::to_string_pretty(&file).map_err(invalid_data)?;
+
+    write_atomically(path, data.as_bytes(), 0o644)
+}
+
 // Function that will return new path in /media/user (used for checking new flash drives in SafeConnection mode)
 pub fn detect_new_media_mount() -> Option<String> {
     let user_mount_path = format!(
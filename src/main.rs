@@ -2,38 +2,41 @@ mod engine;
 mod cli;
 
 use std::{path::Path};
-use std::fs::{File};
-use std::io::{Write, BufWriter};
-use reqwest;
+use std::collections::BTreeSet;
 use std::thread;
 use std::sync::{Arc, Mutex, RwLock};
-use std::collections::HashSet;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use std::thread::JoinHandle;
-use std::collections::HashMap;
 use lazy_static::lazy_static;
 use chrono::Local;
-use std::io::{Cursor, Read};
-use zip::read::ZipArchive;
 use notify_rust::Notification;
 use engine::find_device::find_all_devices;
 use engine::process_checker::scan_processes;
 use cli::cli::run_cli;
-use crate::engine::whitelist::{create_whitelist_from_connected_devices, create_media_whitelist, detect_new_media_mount};
-use crate::engine::filehash::{hash_all_files_in_dir, load_hashes_from_file};
+use cli::logview::run_logview;
+use crate::engine::whitelist::{list_connected_devices, create_media_whitelist, load_whitelist, save_whitelist, DeviceIdentity, MatchPolicy, WhitelistEntry};
+use crate::engine::feeds::{default_feeds, feeds_directory, feeds_file_path, import_legacy_hashes, legacy_hashes_path, load_feeds, lock_feeds, load_signatures, refresh_feeds, rollback_feed, start_feed_refresh, Feed, HashAlgorithm, SignatureSet, DEFAULT_FEED_NAME};
 use crate::engine::process_checker::ProcessScanResult;
-use reqwest::blocking::get;
+use crate::engine::quarantine::load_quarantine;
+use crate::engine::usb_monitor::start_usb_monitor;
+use crate::engine::config::{config_file_path, load_config};
 
 
 // Global log store
 lazy_static! {
     pub static ref LOGS: RwLock<Vec<String>> = RwLock::new(Vec::new());
-    pub static ref HASH_SET: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
-    pub static ref WHITELIST: RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
+    // Scans keep their own pointer to the signatures, so loading new ones never waits for a scan
+    pub static ref SIGNATURES: Arc<Mutex<Option<Arc<SignatureSet>>>> = Arc::new(Mutex::new(None));
+    pub static ref WHITELIST: RwLock<Vec<WhitelistEntry>> = RwLock::new(Vec::new());
     pub static ref FIND_THREAD_RUNNING: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
     pub static ref WHITELIST_READY: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    pub static ref WHITELIST_FIRST_RUN: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
     pub static ref WHITELIST_PATHS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+    // Set when the whitelist was changed outside of the CLI, so it is rendered again
+    pub static ref WHITELIST_CHANGED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+    // Set when whitelist.json could not be read nor moved away, saving would overwrite the trusted devices in it
+    pub static ref WHITELIST_SAVE_BLOCKED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
 }
 
 // Struct to handle the threads in LockDown mode
@@ -44,42 +47,99 @@ pub struct DeviceMonitor {
     scan_results: Arc<Mutex<Vec<ProcessScanResult>>>,
 }
 
-pub fn detect_new_media_mount_main() -> Option<String> {
-    detect_new_media_mount()
-}
-pub fn create_whitelist_from_connected_devices_main() -> HashMap<String, String>{
-    create_whitelist_from_connected_devices()
+pub fn list_connected_devices_main() -> Vec<DeviceIdentity> {
+    list_connected_devices()
 }
 
-/// Downloads SHA256 hashes from Abuse.ch and writes to a local file.
-pub fn download_and_save_hashes(path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let url = "https://bazaar.abuse.ch/export/txt/sha256/full/";
-    let response = get(url)?.bytes()?;
+// Whitelist is stored in the project root next to feeds.json
+pub fn whitelist_file_path() -> String {
+    format!("{}/whitelist.json", env!("CARGO_MANIFEST_DIR"))
+}
 
-    let cursor = Cursor::new(response);
-    let mut archive = ZipArchive::new(cursor)?;
+// Persist the current whitelist, called after every change of it
+pub fn save_whitelist_main() {
+    if WHITELIST_SAVE_BLOCKED.load(Ordering::SeqCst) {
+        push_log(format!("[ERROR] Whitelist was not saved, {} could not be read and would be overwritten. Fix or remove it and restart RustGuardian", whitelist_file_path()));
+        return;
+    }
+    let whitelist = WHITELIST.read().unwrap();
+    if let Err(e) = save_whitelist(Path::new(&whitelist_file_path()), &whitelist) {
+        push_log(format!("[ERROR] Whitelist could not be saved: {}", e));
+    }
+}
 
-    let file = File::create(path)?;
-    let mut writer = BufWriter::new(file);
+// Adds the device to the whitelist and saves it, returns false when it was already trusted.
+// Interfaces are recorded when only some of them were approved, None trusts the whole device
+pub fn whitelist_device_main(identity: DeviceIdentity, policy: MatchPolicy, approved_interfaces: Option<BTreeSet<u8>>) -> bool {
+    {
+        let mut whitelist = WHITELIST.write().unwrap();
+        if whitelist.iter().any(|trusted| trusted.matches(&identity)) {
+            return false;
+        }
+        whitelist.push(WhitelistEntry { identity, policy, approved_interfaces });
+    }
+    save_whitelist_main();
+    WHITELIST_CHANGED.store(true, Ordering::SeqCst);
+    true
+}
 
-    if archive.len() > 0 {
-        let mut zipped_file = archive.by_index(0)?;
-        let mut buffer = String::new();
-        zipped_file.read_to_string(&mut buffer)?;
+// Answer for the first run question, either trust devices that are attached right now or start with empty whitelist
+pub fn finish_whitelist_setup(trust_attached: bool) {
+    {
+        let mut whitelist = WHITELIST.write().unwrap();
+        *whitelist = if trust_attached {
+            list_connected_devices()
+                .into_iter()
+                .map(|identity| WhitelistEntry { identity, policy: MatchPolicy::IgnorePort, approved_interfaces: None })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        push_log(format!("[INFO] Whitelist created with {} trusted devices", whitelist.len()));
+    }
+    save_whitelist_main();
+    WHITELIST_FIRST_RUN.store(false, Ordering::SeqCst);
+    WHITELIST_READY.store(true, Ordering::SeqCst);
+}
 
-        for line in buffer.lines() {
-            let line = line.trim();
-            if !line.starts_with('#') && !line.is_empty() {
-                writeln!(writer, "{}", line)?;
-            }
+// Registry from feeds.json, the MalwareBazaar feed when there is none
+pub fn load_feed_registry() -> Vec<Feed> {
+    match load_feeds(Path::new(&feeds_file_path())) {
+        Ok(Some(feeds)) => {
+            push_log(format!("[INFO] Loaded {} hash feeds from feeds.json", feeds.len()));
+            feeds
+        }
+        Ok(None) => {
+            push_log("[INFO] feeds.json was not found, the MalwareBazaar SHA-256 feed is used".to_string());
+            default_feeds()
+        }
+        Err(e) => {
+            push_log(format!("[ERROR] feeds.json could not be loaded: {}. The MalwareBazaar SHA-256 feed is used", e));
+            default_feeds()
         }
-    } else {
-        return Err("ZIP archive is empty".into());
     }
+}
 
+// Puts back the previous copy of the feed and loads the signatures again
+pub fn rollback_feed_main(name: &str) -> Result<(), String> {
+    let feeds = load_feed_registry();
+    let feed = feeds.iter().find(|feed| feed.name == name).ok_or_else(|| format!("No feed {}", name))?;
+    // Refresh thread could be replacing the same copy right now
+    let _feeds = lock_feeds();
+    rollback_feed(feed, &feeds_directory()).map_err(|e| format!("Feed {} could not be rolled back: {}", name, e))?;
+    push_log(format!("[ACTION] Feed {} was rolled back to its previous version", name));
+    install_signatures(load_signatures(&feeds, &feeds_directory()));
     Ok(())
 }
 
+// Scans started from now on use the new signatures, running scans finish with the ones they have
+pub fn install_signatures(signatures: SignatureSet) {
+    // Inserting hash of our non malicous file for testing
+    let signatures = signatures.with_extra(HashAlgorithm::Sha256, &["195c291a262a846cefa5b42fc8a74293cf91bfe44d49c71ed56a07b588b1ecba"]);
+    push_log(format!("[INFO] {} hashes of {} feeds are used for scans", signatures.len(), signatures.feeds.len()));
+    *SIGNATURES.lock().unwrap() = Some(Arc::new(signatures));
+}
+
 // Methods for the DeviceMonitor structure 
 impl DeviceMonitor {
     pub fn new() -> Self {
@@ -166,29 +226,6 @@ impl DeviceMonitor {
     }
 }
 
-// Run a file hash check in a separate thread
-// Basically this starts a thread that will hash all files in the directory on the flash drive
-// So our program could then compare hashes to the hashes of viruses
-pub fn start_hash_checker(dir: &Path) {
-    let hash_set_clone = {
-        let hash_set = HASH_SET.lock().unwrap();
-        if hash_set.is_empty() {
-            push_log("There is no file to compare hashes, \
-                      please ensure that you have them or enable internet connection on the device so program could download them".to_string());
-            return;
-        }
-        hash_set.clone() 
-    };
-    let path = dir.to_path_buf();
-    let _hash_thread = thread::spawn(move || 
-        {
-            
-            let _ = hash_all_files_in_dir(&path, &hash_set_clone);
-            push_log(format!("[INFO] Finished checking {}", path.display()));
-        }
-    );
-}
-
 // Push a new log entry with a timestamp for the TUI CLI interface
 pub fn push_log(msg: String) {
     let mut logs = LOGS.write().unwrap();
@@ -210,6 +247,18 @@ pub fn clear_logs() {
 
 
 fn main() {
+    // Session logs are read without starting the monitoring
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("logview") {
+        if let Err(e) = load_config() {
+            eprintln!("config.json could not be loaded: {}. Default settings are used", e);
+        }
+        if let Err(e) = run_logview(&args[2..]) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
     // Start the CLI interface in a separate thread
     let cli_thread = thread::spawn(|| {
         if let Err(e) = run_cli() {
@@ -217,70 +266,81 @@ fn main() {
         }
     });
     push_log("[INFO] Preparing the RustGuardian for work, please wait".to_string());
+    // Listen to USB connections and disconnections for the whole run
+    let usb_monitor_running = Arc::new(AtomicBool::new(true));
+    let usb_monitor_thread = start_usb_monitor(usb_monitor_running.clone());
 
-    // Load file hashes from project root. This operation takes about 8 seconds
-    let device_hashes_path = format!("{}/hashes.txt", env!("CARGO_MANIFEST_DIR"));
-    let mut hashes_exists = false;
-    let mut update_needed = false;
-
-    if Path::new(&device_hashes_path).exists() {
-        push_log("[INFO] Hashes.txt was found on device".to_string());
-        hashes_exists = true;
-        if let Ok(metadata) = std::fs::metadata(&device_hashes_path) {
-            if let Ok(modified_time) = metadata.modified() {
-                use std::time::{SystemTime, Duration};
-                if let Ok(elapsed) = SystemTime::now().duration_since(modified_time) {
-                    if elapsed > Duration::from_secs(48 * 3600) {
-                        push_log("[WARNING] Hashes.txt is older than 48 hours, updating...".to_string());
-                        update_needed = true;
-                    } else {
-                        push_log("[INFO] Hashes.txt is fresh".to_string());
-                    }
-                }
-            }
-        }
-    } 
-    if update_needed || !hashes_exists {
-        match download_and_save_hashes(&device_hashes_path) {
-            Ok(_) => {
-                push_log("[INFO] Hashes are downloaded from abuse.ch".to_string());
-                hashes_exists = true;
-                update_needed = false;
-            }
-            Err(e) => push_log(format!("[ERROR] Error when trying to load hashes: {}", e)),
-        }
+    match load_config() {
+        Ok(true) => push_log(format!("[INFO] Settings loaded from {}", config_file_path())),
+        Ok(false) => push_log("[INFO] config.json was not found, default settings are used".to_string()),
+        Err(e) => push_log(format!("[ERROR] config.json could not be loaded: {}. Default settings are used", e)),
     }
 
-    if hashes_exists {
-        if update_needed {
-            push_log("[WARNING] The local hash database is older than 48 hours. Please connect to the internet to update it. RustGuardian \
-            will continue working with the outdated hashes for now.".to_string());
+    // Feeds that are due are downloaded, then the indexes of all feeds are mapped
+    let feeds = load_feed_registry();
+    {
+        // Rollback typed in the TUI waits until the feeds are ready
+        let _feeds = lock_feeds();
+        // hashes.txt of an older version is used until the default feed is downloaded
+        match import_legacy_hashes(&feeds, &feeds_directory(), &legacy_hashes_path()) {
+            Ok(0) => {}
+            Ok(count) => push_log(format!("[INFO] {} hashes from hashes.txt were imported into feed {}, hashes.txt and hashes.idx are not used anymore", count, DEFAULT_FEED_NAME)),
+            Err(e) => push_log(format!("[WARNING] hashes.txt could not be imported: {}", e)),
         }
         push_log("[INFO] Extracting hashes, please wait".to_string());
-        {
-            let mut hash_set = HASH_SET.lock().unwrap();
-            *hash_set = load_hashes_from_file(&device_hashes_path);
-            // Inserting hash of our non malicous file for testing
-            hash_set.insert("195c291a262a846cefa5b42fc8a74293cf91bfe44d49c71ed56a07b588b1ecba".to_string());
-            push_log(format!("[INFO] Loaded {} hashes into memory", hash_set.len()));
+        let signatures = refresh_feeds(&feeds, &feeds_directory());
+        if signatures.is_empty() {
+            push_log("[ERROR] Hashes could not be extracted. The SafeConnection mode is disabled".to_string());
+        } else {
+            install_signatures(signatures);
         }
     }
-    else{
-        push_log("[ERROR] Hashes could not be extracted. The SafeConnection mode is disabled".to_string());
-    }
+    let feed_refresh_running = Arc::new(AtomicBool::new(true));
+    let feed_refresh_thread = start_feed_refresh(feeds, feed_refresh_running.clone(), install_signatures);
     push_log("[INFO] Initializing whitelist, please wait".to_string());
     // Initialize whitelist once
     {
-        let whitelist_set: HashMap<String, String> = create_whitelist_from_connected_devices();
-        let mut whitelist = WHITELIST.write().unwrap();
-        *whitelist = whitelist_set;
         let whitelist_media_vector: Vec<String> = create_media_whitelist();
         let mut whitelist_media = WHITELIST_PATHS.write().unwrap();
         *whitelist_media = whitelist_media_vector;
-        WHITELIST_READY.store(true, Ordering::SeqCst);
+    }
+    match load_whitelist(Path::new(&whitelist_file_path())) {
+        Ok(Some(whitelist_set)) => {
+            push_log(format!("[INFO] Loaded {} trusted devices from whitelist.json", whitelist_set.len()));
+            *WHITELIST.write().unwrap() = whitelist_set;
+            WHITELIST_READY.store(true, Ordering::SeqCst);
+        }
+        Ok(None) => {
+            // First run, the user has to decide if the devices attached right now are trusted
+            WHITELIST_FIRST_RUN.store(true, Ordering::SeqCst);
+            push_log("[SETUP] No whitelist was found. Trust currently attached devices? \
+                      Type 'trust all' to add them to the whitelist or 'trust none' to start with an empty whitelist".to_string());
+        }
+        Err(e) => {
+            // Broken file is kept for the user instead of being overwritten by the next change of the whitelist
+            let bad_path = format!("{}.bad", whitelist_file_path());
+            let moved = e.kind() == std::io::ErrorKind::InvalidData && std::fs::rename(whitelist_file_path(), &bad_path).is_ok();
+            if moved {
+                push_log(format!("[ERROR] Whitelist could not be loaded: {}. It was moved to {}, starting with an empty whitelist", e, bad_path));
+            } else {
+                WHITELIST_SAVE_BLOCKED.store(true, Ordering::SeqCst);
+                push_log(format!("[ERROR] Whitelist could not be loaded: {}. Starting with an empty whitelist, changes will not be saved", e));
+            }
+            WHITELIST_READY.store(true, Ordering::SeqCst);
+        }
+    }
+
+    match load_quarantine() {
+        Ok(0) => {}
+        Ok(count) => push_log(format!("[INFO] {} devices are in quarantine, use 'blocked' to list them", count)),
+        Err(e) => push_log(format!("[ERROR] Quarantine could not be loaded: {}", e)),
     }
 
     push_log("The RustGuardian is prepared to guard your connections!".to_string());
     // Wait for CLI thread to finish
     let _ = cli_thread.join();
+    usb_monitor_running.store(false, Ordering::SeqCst);
+    let _ = usb_monitor_thread.join();
+    feed_refresh_running.store(false, Ordering::SeqCst);
+    let _ = feed_refresh_thread.join();
 }